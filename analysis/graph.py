@@ -1,28 +1,26 @@
-import matplotlib.pyplot as plt
-
+import csv
+import sys
 
-def replay_name(gen, idx):
-    return f"replay/replay_w10_{gen}_{idx}.txt"
+import matplotlib.pyplot as plt
 
+# Plots a score table written by `cargo run --release -- score`, e.g.
+#     python analysis/graph.py "Training linear/Aeon 5/Scores width 10 bag.csv"
 
-generations = list(range(20))
-nums = 5
+with open(sys.argv[1], "r") as f:
+    rows = list(csv.DictReader(f))
 
 xs = []
 ys = []
 avgx = []
 avgy = []
 
-for i in generations:
-    score_sum = 0
-    for j in range(nums):
-        with open(replay_name(i, j), "r") as f:
-            score = int(f.readline().rstrip())
-            score_sum += score
-            xs.append(i)
-            ys.append(score)
-    avgx.append(i)
-    avgy.append(score_sum / nums)
+for row in rows:
+    gen = int(row["generation"])
+    for score in row["scores"].split():
+        xs.append(gen)
+        ys.append(int(score))
+    avgx.append(gen)
+    avgy.append(float(row["mean"]))
 
 
 fig, ax = plt.subplots()
@@ -30,6 +28,5 @@ ax.plot(xs, ys, "+")
 ax.plot(avgx, avgy)
 ax.set_xlabel("Generations", fontsize=20)
 ax.set_ylabel("Average score", fontsize=20)
-ax.set_title("Average score for generations (width 10)", fontsize=20)
-ax.set_xticks(list(range(0, 21, 5)))
+ax.set_title("Average score for generations", fontsize=20)
 plt.show()
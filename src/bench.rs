@@ -0,0 +1,277 @@
+// Benchmarking beam selection on recorded beams.
+
+// TopK used to keep its states in a BTreeMap, with a BTreeSet per family for the cap, which paid a
+// logarithmic insert, and often a removal, for every child. That implementation is kept here as
+// BTreeTopK, so the two can be compared on real children:
+//		cargo run --release -- bench-topk 12
+//		cargo run --release -- bench-topk 12 10
+// For a sample of the depths a generation's master search recorded (move_N.bin, 5 by default), the
+// beam is expanded once with that generation's network, and the children are then selected with
+// both implementations, by one thread and split into THREAD_NUMBER buffers merged at the end.
+// The selections must agree; the command exits with status 1 if they do not.
+
+use crate::constants::{MULTIPLIER, NET_VERSION, THREAD_NUMBER};
+use crate::database::load_states;
+use crate::emulator::network_heuristic;
+use crate::topk::TopK;
+use crate::types::{FamilyKey, SearchConf, StateH, WeightT};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::time::Instant;
+
+use fnv::FnvHashMap;
+use savefile::prelude::*;
+
+#[derive(Clone, Debug)]
+pub struct BTreeTopK<V> {
+    capacity: usize,
+    family_key: FamilyKey,
+    family_cap: usize,
+    states: BTreeMap<StateH, (usize, u64, V)>,
+    families: FnvHashMap<u64, BTreeSet<StateH>>,
+}
+
+impl<V> BTreeTopK<V> {
+    pub fn for_search(conf: &SearchConf) -> BTreeTopK<V> {
+        BTreeTopK {
+            capacity: conf.beam_width,
+            family_key: conf.family_key,
+            family_cap: conf.family_cap,
+            states: BTreeMap::new(),
+            families: FnvHashMap::default(),
+        }
+    }
+
+    pub fn insert(&mut self, state: StateH, origin: usize, value: V) {
+        if let Some(existing) = self.states.get_mut(&state) {
+            if origin < existing.0 {
+                existing.0 = origin;
+                existing.2 = value;
+            }
+            return;
+        }
+
+        let family = if self.family_cap > 0 {
+            self.family_key.family(&state.well)
+        } else {
+            0
+        };
+        if self.family_cap > 0 {
+            let members = self.families.get(&family).map_or(0, |f| f.len());
+            if members == self.family_cap {
+                let worst = self.families[&family].first().unwrap().clone();
+                if state < worst {
+                    return;
+                }
+                self.remove(&worst);
+            }
+        }
+
+        if self.states.len() == self.capacity {
+            match self.states.first_key_value() {
+                Some((worst, _)) if state > *worst => {
+                    let worst = worst.clone();
+                    self.remove(&worst);
+                }
+                _ => return,
+            }
+        }
+
+        if self.family_cap > 0 {
+            self.families.entry(family).or_default().insert(state.clone());
+        }
+        self.states.insert(state, (origin, family, value));
+    }
+
+    fn remove(&mut self, state: &StateH) {
+        let (_, family, _) = self.states.remove(state).unwrap();
+        if self.family_cap > 0 {
+            let members = self.families.get_mut(&family).unwrap();
+            members.remove(state);
+            if members.is_empty() {
+                self.families.remove(&family);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: BTreeTopK<V>) {
+        for (state, (origin, _, value)) in other.states {
+            self.insert(state, origin, value);
+        }
+    }
+
+    // From the worst state to the best.
+    pub fn into_vec(self) -> Vec<(StateH, V)> {
+        self.states.into_iter().map(|(state, (_, _, value))| (state, value)).collect()
+    }
+}
+
+// The operations the benchmark times, shared by TopK and BTreeTopK.
+trait Buffer {
+    fn for_search(conf: &SearchConf) -> Self;
+    fn insert(&mut self, state: StateH, origin: usize);
+    fn merge(&mut self, other: Self);
+    fn into_vec(self) -> Vec<(StateH, usize)>;
+}
+
+impl Buffer for TopK<usize> {
+    fn for_search(conf: &SearchConf) -> Self {
+        TopK::for_search(conf)
+    }
+
+    fn insert(&mut self, state: StateH, origin: usize) {
+        TopK::insert(self, state, origin, origin)
+    }
+
+    fn merge(&mut self, other: Self) {
+        TopK::merge(self, other)
+    }
+
+    fn into_vec(self) -> Vec<(StateH, usize)> {
+        TopK::into_vec(self)
+    }
+}
+
+impl Buffer for BTreeTopK<usize> {
+    fn for_search(conf: &SearchConf) -> Self {
+        BTreeTopK::for_search(conf)
+    }
+
+    fn insert(&mut self, state: StateH, origin: usize) {
+        BTreeTopK::insert(self, state, origin, origin)
+    }
+
+    fn merge(&mut self, other: Self) {
+        BTreeTopK::merge(self, other)
+    }
+
+    fn into_vec(self) -> Vec<(StateH, usize)> {
+        BTreeTopK::into_vec(self)
+    }
+}
+
+// Selects children, given with the index of the well they came from, in parts buffers merged at
+// the end, returning the selection and the seconds it took.
+
+fn select<B: Buffer>(children: &[(StateH, usize)], parts: usize, conf: &SearchConf) -> (Vec<(StateH, usize)>, f64) {
+    let start = Instant::now();
+    let mut buffers: Vec<B> = (0..parts).map(|_| B::for_search(conf)).collect();
+    let chunk = children.len().div_ceil(parts).max(1);
+    for (b, part) in children.chunks(chunk).enumerate() {
+        for (state, origin) in part {
+            buffers[b].insert(state.clone(), *origin);
+        }
+    }
+    let mut best = buffers.pop().unwrap();
+    for buffer in buffers {
+        best.merge(buffer);
+    }
+    let selection = best.into_vec();
+    (selection, start.elapsed().as_secs_f64())
+}
+
+pub fn topk_bench_command(args: &[String]) {
+    let usage = "Usage: bench-topk GENERATION [DEPTHS]";
+    let generation: usize = args
+        .first()
+        .and_then(|g| g.parse().ok())
+        .unwrap_or_else(|| panic!("{}", usage));
+    let samples: usize = args.get(1).map_or(5, |d| d.parse().unwrap_or_else(|_| panic!("{}", usage)));
+
+    let conf = SearchConf::master(generation);
+    let weight: WeightT = load_file(&conf.neural_network_path(), NET_VERSION)
+        .unwrap_or_else(|_| panic!("Could not load {}.", conf.neural_network_path()));
+    let mut recorded = 0;
+    while Path::new(&conf.move_path(recorded)).exists() {
+        recorded += 1;
+    }
+    if recorded == 0 {
+        panic!("No recorded beams in {}.", conf.replay_path());
+    }
+    let step = recorded.div_ceil(samples.max(1));
+
+    println!("Beam width: {}, family cap: {}", conf.beam_width, conf.family_cap);
+    println!("Depth   Wells   Children  BTree s  TopK s  Speedup  BTree x{} s  TopK x{} s  Speedup", THREAD_NUMBER, THREAD_NUMBER);
+
+    let mut mismatch = false;
+    for depth in (0..recorded).step_by(step) {
+        let wells = load_states(&conf.move_path(depth));
+        let mut children = vec![];
+        for (w, well) in wells.iter().enumerate() {
+            for (node, h) in network_heuristic(well, &weight, &conf, depth, None, None) {
+                let state = StateH {
+                    well: *node.well(),
+                    score: node.score,
+                    hash: node.hash(),
+                    heuristic: (h * MULTIPLIER) as i64,
+                };
+                children.push((state, w));
+            }
+        }
+
+        let (btree, btree_seconds) = select::<BTreeTopK<usize>>(&children, 1, &conf);
+        let (new, new_seconds) = select::<TopK<usize>>(&children, 1, &conf);
+        let (btree_parts, btree_parts_seconds) = select::<BTreeTopK<usize>>(&children, THREAD_NUMBER, &conf);
+        let (new_parts, new_parts_seconds) = select::<TopK<usize>>(&children, THREAD_NUMBER, &conf);
+        let flag = if btree == new && btree_parts == new_parts && btree == btree_parts {
+            ""
+        } else {
+            "  MISMATCH"
+        };
+        mismatch |= !flag.is_empty();
+        println!(
+            "{:5}  {:6}  {:9}  {:7.3}  {:6.3}  {:6.1}x  {:9.3}  {:8.3}  {:6.1}x{}",
+            depth,
+            wells.len(),
+            children.len(),
+            btree_seconds,
+            new_seconds,
+            btree_seconds / new_seconds,
+            btree_parts_seconds,
+            new_parts_seconds,
+            btree_parts_seconds / new_parts_seconds,
+            flag
+        );
+    }
+
+    if mismatch {
+        println!("The selections disagree.");
+        std::process::exit(1);
+    }
+    println!("The selections agree.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::geometry;
+    use crate::zobrist::well_hash;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn btree_and_topk_select_alike() {
+        let g = geometry();
+        let mut rng = StdRng::seed_from_u64(19);
+        for trial in 0..100 {
+            let mut conf = SearchConf::testing();
+            conf.beam_width = rng.gen_range(1..40);
+            conf.family_key = FamilyKey::TopSurface;
+            conf.family_cap = trial % 3;
+
+            let children: Vec<(StateH, usize)> = (0..rng.gen_range(0..500))
+                .map(|_| {
+                    let mut state = StateH::new();
+                    state.well[g.eff_height - 1] = rng.gen_range(1..8);
+                    state.well[g.eff_height - 2] = rng.gen_range(0..4);
+                    state.hash = well_hash(&state.well);
+                    state.heuristic = (state.hash % 31) as i64;
+                    (state, rng.gen_range(0..100))
+                })
+                .collect();
+            assert_eq!(select::<TopK<usize>>(&children, 3, &conf).0, select::<BTreeTopK<usize>>(&children, 1, &conf).0);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Head-to-head comparison of two networks.
+
+// Both networks play the same games at the same beam width (see playback::play_games): game k draws
+// its pieces from a 7-bag seeded by the run seed and k, or with hatetris both play the one HATETRIS game.
+// Each game is a win for the new network if its score is higher, a loss if lower and a draw otherwise.
+// Counting a win as 1, a draw as 1/2 and a loss as 0, the mean is the new network's points rate. Draws say
+// nothing about which network is better, so the test is on the games that were not draws: the share of them
+// the new network won, with a 95% Wilson score interval. The mean score difference gets an interval from the
+// normal approximation, for information only.
+//		cargo run --release -- compare 12
+//		cargo run --release -- compare 12 9 games:50 width:100
+//		cargo run --release -- compare "Training linear/Aeon 4/Generation 3/Network 3.bin" 12 hatetris
+// A network is a generation of the aeon or the path to a saved network; the old one defaults to the
+// generation before the new one, the games to 20 and the width to MASTER_BEAM_WIDTH.
+// With PROMOTION_GAMES above 0, training_cycle() compares every newly trained network with the one it was
+// trained from, and keeps the old one if the new one is significantly worse, see Comparison::promotes().
+
+use crate::constants::PROMOTION_MIN_DECISIVE;
+use crate::neural::load_network;
+use crate::playback::play_games;
+use crate::seed::{init_seed, run_seed};
+use crate::types::{ScoreT, SearchConf, WeightT};
+
+use std::path::Path;
+
+// Half the width of a two-sided 95% interval, in standard errors.
+const Z_95: f64 = 1.96;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub new_scores: Vec<ScoreT>,
+    pub old_scores: Vec<ScoreT>,
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize,
+}
+
+// The mean of values with its 95% interval; a single value has an interval of width 0.
+fn interval(values: &[f64]) -> (f64, f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    if values.len() < 2 {
+        return (mean, mean, mean);
+    }
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let half = Z_95 * (variance / n).sqrt();
+    (mean, mean - half, mean + half)
+}
+
+impl Comparison {
+    pub fn new(new_scores: Vec<ScoreT>, old_scores: Vec<ScoreT>) -> Comparison {
+        let pairs = || new_scores.iter().zip(old_scores.iter());
+        let wins = pairs().filter(|(n, o)| n > o).count();
+        let draws = pairs().filter(|(n, o)| n == o).count();
+        let losses = pairs().filter(|(n, o)| n < o).count();
+        Comparison {
+            new_scores,
+            old_scores,
+            wins,
+            draws,
+            losses,
+        }
+    }
+
+    // The points rate of the new network.
+    pub fn points(&self) -> f64 {
+        (self.wins as f64 + 0.5 * self.draws as f64) / self.new_scores.len() as f64
+    }
+
+    // The share of the games that were not draws won by the new network, with its 95% Wilson score
+    // interval, or None if every game was a draw.
+    pub fn win_rate(&self) -> Option<(f64, f64, f64)> {
+        let decisive = (self.wins + self.losses) as f64;
+        if decisive == 0.0 {
+            return None;
+        }
+        let rate = self.wins as f64 / decisive;
+        let z2 = Z_95 * Z_95;
+        let centre = (rate + z2 / (2.0 * decisive)) / (1.0 + z2 / decisive);
+        let half = Z_95 / (1.0 + z2 / decisive)
+            * (rate * (1.0 - rate) / decisive + z2 / (4.0 * decisive * decisive)).sqrt();
+        Some((rate, (centre - half).max(0.0), (centre + half).min(1.0)))
+    }
+
+    // The mean of new score - old score over the games and its interval.
+    pub fn score_difference(&self) -> (f64, f64, f64) {
+        let differences: Vec<f64> = self
+            .new_scores
+            .iter()
+            .zip(self.old_scores.iter())
+            .map(|(&n, &o)| n as f64 - o as f64)
+            .collect();
+        interval(&differences)
+    }
+
+    // The new network is promoted unless it is significantly worse: at least PROMOTION_MIN_DECISIVE games
+    // were not draws, and the whole interval of its win rate is below 1/2. Requiring it to be significantly
+    // better would stall early generations, whose games are mostly draws.
+    pub fn promotes(&self) -> bool {
+        match self.win_rate() {
+            Some((_, _, high)) if self.wins + self.losses >= PROMOTION_MIN_DECISIVE => high >= 0.5,
+            _ => true,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let (difference, difference_low, difference_high) = self.score_difference();
+        let mean = |scores: &Vec<ScoreT>| scores.iter().map(|&s| s as f64).sum::<f64>() / scores.len() as f64;
+        let win_rate = match self.win_rate() {
+            Some((rate, low, high)) if self.wins + self.losses >= PROMOTION_MIN_DECISIVE => {
+                format!("{:.3} (95% interval {:.3} to {:.3})", rate, low, high)
+            }
+            _ => format!("fewer than {} games were not draws, not significant", PROMOTION_MIN_DECISIVE),
+        };
+        format!(
+            "Games: {}, wins: {}, draws: {}, losses: {}\n\
+             Points rate: {:.3}, win rate without draws: {}\n\
+             Mean score: {:.2} new, {:.2} old, difference {:.2} (95% interval {:.2} to {:.2})\n",
+            self.new_scores.len(),
+            self.wins,
+            self.draws,
+            self.losses,
+            self.points(),
+            win_rate,
+            mean(&self.new_scores),
+            mean(&self.old_scores),
+            difference,
+            difference_low,
+            difference_high
+        )
+    }
+}
+
+// Plays both networks on the same games with conf, see play_games().
+
+pub fn compare_networks(
+    new: &WeightT,
+    old: &WeightT,
+    conf: &SearchConf,
+    hatetris: bool,
+    games: usize,
+) -> Comparison {
+    let new_scores = play_games(new, conf, hatetris, games);
+    let old_scores = play_games(old, conf, hatetris, games);
+    Comparison::new(new_scores, old_scores)
+}
+
+pub fn compare_command(args: &[String]) {
+    let usage = "Usage: compare NEW [OLD] [hatetris] [games:N] [width:N]\n\
+                 NEW and OLD are generations of the aeon or paths to saved networks.";
+    let fail = |e: String| -> ! {
+        println!("{}\n{}", e, usage);
+        std::process::exit(1)
+    };
+    let mut networks: Vec<&String> = vec![];
+    let mut hatetris = false;
+    let mut games = 20;
+    let mut conf = SearchConf::master(0);
+    conf.save = false;
+    conf.print = false;
+    for arg in args {
+        if let Some(n) = arg.strip_prefix("games:") {
+            games = n.parse().unwrap_or_else(|_| fail(format!("Bad game count {}.", n)));
+        } else if let Some(width) = arg.strip_prefix("width:") {
+            conf.beam_width = width.parse().unwrap_or_else(|_| fail(format!("Bad beam width {}.", width)));
+        } else if arg == "hatetris" {
+            hatetris = true;
+        } else if networks.len() < 2 {
+            networks.push(arg);
+        } else {
+            fail(format!("Unknown argument {}.", arg));
+        }
+    }
+    if hatetris {
+        games = 1;
+    }
+    if games == 0 {
+        fail(String::from("There must be at least one game."));
+    }
+
+    // A generation number or a path.
+    let path = |network: &str| -> String {
+        match network.parse::<usize>() {
+            Ok(generation) => {
+                let mut c = conf.clone();
+                c.generation = generation;
+                c.neural_network_path()
+            }
+            Err(_) => String::from(network),
+        }
+    };
+    let new_path = path(networks.first().unwrap_or_else(|| fail(String::from("No network given."))));
+    let old_path = match networks.get(1) {
+        Some(old) => path(old),
+        None => match networks[0].parse::<usize>() {
+            Ok(generation) if generation > 0 => path(&(generation - 1).to_string()),
+            _ => fail(String::from("The old network must be given.")),
+        },
+    };
+
+    // Games are only reproducible with the aeon's seed; elsewhere, SEED can fix it.
+    let seed = if Path::new(&conf.aeon_path()).exists() {
+        init_seed(&conf.seed_path())
+    } else {
+        run_seed()
+    };
+    println!("Run seed: {}", seed);
+    println!("New: {}", new_path);
+    println!("Old: {}", old_path);
+    println!("{} games, beam width {}", games, conf.beam_width);
+
+    let new = load_network(&new_path);
+    let old = load_network(&old_path);
+    let comparison = compare_networks(&new, &old, &conf, hatetris, games);
+    println!("New scores: {:?}", comparison.new_scores);
+    println!("Old scores: {:?}", comparison.old_scores);
+    print!("{}", comparison.report());
+    println!("The new network {} be promoted.", if comparison.promotes() { "would" } else { "would not" });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_count_each_game() {
+        let comparison = Comparison::new(vec![3, 5, 2, 7, 4], vec![1, 5, 4, 2, 0]);
+        assert_eq!((comparison.wins, comparison.draws, comparison.losses), (3, 1, 1));
+        assert_eq!(comparison.points(), 0.7);
+        // 3 wins of 4: centre (0.75 + z²/8) / (1 + z²/4).
+        let (rate, low, high) = comparison.win_rate().unwrap();
+        let z2 = Z_95 * Z_95;
+        assert_eq!(rate, 0.75);
+        assert!(((low + high) / 2.0 - (0.75 + z2 / 8.0) / (1.0 + z2 / 4.0)).abs() < 1e-12);
+        assert!(low < rate && high < 1.0);
+        // Differences 2, 0, -2, 5, 4: mean 1.8, sample variance 8.2.
+        let (difference, low, high) = comparison.score_difference();
+        assert!((difference - 1.8).abs() < 1e-12);
+        assert!((high - low - 2.0 * Z_95 * (8.2f64 / 5.0).sqrt()).abs() < 1e-12);
+        assert!(comparison.promotes());
+
+        assert!(Comparison::new(vec![0; 8], vec![0; 8]).promotes());
+        assert_eq!(Comparison::new(vec![0; 8], vec![0; 8]).win_rate(), None);
+        assert!(!Comparison::new(vec![1; 8], vec![2; 8]).promotes());
+        assert_eq!(Comparison::new(vec![4], vec![2]).points(), 1.0);
+    }
+
+    #[test]
+    fn few_decisive_games_promote() {
+        // The one HATETRIS game, lost.
+        assert!(Comparison::new(vec![1], vec![2]).promotes());
+        assert!(Comparison::new(vec![1; 2], vec![2; 2]).promotes());
+        // 4 losses of 4 would be significant, but are too few games.
+        assert!(Comparison::new(vec![1; 4], vec![2; 4]).win_rate().unwrap().2 < 0.5);
+        assert!(Comparison::new(vec![1; 4], vec![2; 4]).promotes());
+        assert!(!Comparison::new(vec![1; 5], vec![2; 5]).promotes());
+        // Draws do not count towards the minimum.
+        assert!(Comparison::new(vec![1, 1, 1, 1, 0, 0, 0], vec![2, 2, 2, 2, 0, 0, 0]).promotes());
+    }
+}
@@ -1,50 +1,31 @@
-use crate::types::{RowT, WaveT};
+use crate::types::{FamilyKey, PieceSelector, ScoringRule};
+
+use std::time::Duration;
 
 // WELL GEOMETRY
 
-// The RowT and WaveT types from types.rs MUST match the well width w:
-//		RowT: Needs w + 1 bits.
-//		WaveT: Needs 4*(w + 2*3 - 4) = 4*w + 8 bits.
+// These are only the defaults; the geometry in use is chosen at startup, see geometry.rs.
+// The RowT and WaveT types from types.rs MUST fit the well width w:
+//		RowT: Needs w + 2 bits, since pieces can hang up to two columns past the left wall.
+//		WaveT: Needs 4*w + 4 bits, the size of a waveform.
 // For instance, a well of standard width 10 would need:
-//		RowT: 10 + 1 bits -> u16
+//		RowT: 10 + 2 bits -> u16
 //		WaveT: 4*10 + 4 = 44 -> u64.
+// u16/u64 allow widths up to 14; the "wide" cargo feature switches to u32/u128 for widths up to 30.
 
-pub const WELL_HEIGHT: usize = 20;
-pub const WELL_LINE: usize = 4;
-
-pub const WIDTH: usize = 10;
-pub const WAVE_SIZE: usize = 4 * WIDTH + 4;
-
-pub const MAX_ROW: RowT = (2 as RowT).pow(WIDTH as u32) - 1;
-pub const EFF_HEIGHT: usize = WELL_HEIGHT - WELL_LINE;
+pub const DEFAULT_WELL_HEIGHT: usize = 20;
+pub const DEFAULT_WELL_LINE: usize = 4;
+pub const DEFAULT_WIDTH: usize = 10;
 
-// ROTATE_LEFT:   0b000100010001...
-// ROTATE_RIGHT:  0b111011101110...
-
-// WAVE_OFFSET used to be (16^(w+1) - 1)/15, but the w+1 limited well width too much.
-// Now it is 16 * (16^w - 1)/15 + 1
-
-pub const WAVE_OFFSET: WaveT = 1 + 16 * ((16 as WaveT).pow((WIDTH) as u32) - 1) / 15;
-pub const ROTATE_LEFT: WaveT = WAVE_OFFSET * 1;
-pub const ROTATE_RIGHT: WaveT = WAVE_OFFSET * 14;
+// Rows stored in every WellT. Wells whose height below the line is smaller leave the remaining rows empty.
+// Changing this changes the layout of all saved wells.
+pub const MAX_EFF_HEIGHT: usize = 16;
 
 // NEURAL NET PARAMETERS
 
 pub const HIDDEN: usize = 48;
-pub const CONVOLUTIONS: [(usize, usize); 2] = [(1, 10), (4, 3)]; // The order of arguments matters!
-
-// I don't like hardcoding the count, but I don't see a way around it.
-pub const CONV_POW: [usize; 2] = [
-    2_usize.pow((CONVOLUTIONS[0].0 * CONVOLUTIONS[0].1) as u32),
-    2_usize.pow((CONVOLUTIONS[1].0 * CONVOLUTIONS[1].1) as u32),
-];
-pub const CONV_COUNT: usize = (EFF_HEIGHT - CONVOLUTIONS[0].0 + 1)
-    * (WIDTH - CONVOLUTIONS[0].1 + 1)
-    + (EFF_HEIGHT - CONVOLUTIONS[1].0 + 1) * (WIDTH - CONVOLUTIONS[1].1 + 1);
-pub const ALL_CONV: usize =
-    CONV_POW[0] * (EFF_HEIGHT - CONVOLUTIONS[0].0 + 1) * (WIDTH - CONVOLUTIONS[0].1 + 1)
-        + CONV_POW[1] * (EFF_HEIGHT - CONVOLUTIONS[1].0 + 1) * (WIDTH - CONVOLUTIONS[1].1 + 1);
-pub const WEIGHT_COUNT: usize = HIDDEN * ALL_CONV;
+// The order of arguments matters! Narrower or shorter wells clamp the convolutions to fit.
+pub const CONVOLUTIONS: [(usize, usize); 2] = [(1, 10), (4, 3)];
 
 pub const ALPHA: f64 = 0.0001;
 pub const EPS: f64 = 0.00000001;
@@ -67,6 +48,32 @@ pub const TRAINING_BEAM_DEPTH: usize = 5;
 
 pub const AEON: usize = 0;
 
+// Each newly trained network plays PROMOTION_GAMES seeded games against the network it was trained from,
+// at PROMOTION_BEAM_WIDTH, and replaces it only if it is not significantly worse (see compare.rs);
+// 0 promotes every network. With PROMOTION_HATETRIS, they play the one HATETRIS game instead.
+pub const PROMOTION_GAMES: usize = 0;
+pub const PROMOTION_BEAM_WIDTH: usize = MASTER_BEAM_WIDTH;
+pub const PROMOTION_HATETRIS: bool = false;
+// Games that are not draws needed before a network can be found significantly worse; with fewer, it is promoted.
+pub const PROMOTION_MIN_DECISIVE: usize = 5;
+
+// Piece selection used by every search. PieceSelector::Hatetris plays the real game;
+// the other selectors are for experiments against friendlier piece sequences.
+pub const PIECE_SELECTOR: PieceSelector = PieceSelector::Random;
+
+// HATETRIS loop prevention for searches that keep parents; only affects PieceSelector::Hatetris.
+pub const LOOP_PREVENTION: bool = true;
+
+// Master searches keep at most FAMILY_CAP wells of any one family in the beam, 0 for no cap,
+// so the beam does not collapse onto near-duplicates. FAMILY_KEY decides what a family is,
+// and also groups the "Family distribution" printed for each depth.
+pub const FAMILY_KEY: FamilyKey = FamilyKey::BottomRow;
+pub const FAMILY_CAP: usize = 0;
+
+// Points for clearing lines. HATETRIS scores one point per line; the SCORING environment variable
+// (linear, squared or classic) chooses another rule at startup.
+pub const DEFAULT_SCORING: ScoringRule = ScoringRule::Linear;
+
 // FILE NAMING AND VERSIONING
 
 pub const BEAM_WIDTH: usize = 10_000;
@@ -76,22 +83,28 @@ pub const CHECKPOINTS: &str = "Training";
 pub const RUN_TYPE: &str = "recursive_heuristic_2/"; // Only for non-neural-network runs.
 pub const SAVE_RUN: bool = false;
 
-pub const VERSION: u32 = (WELL_HEIGHT << 16 + WIDTH << 8 + 0) as u32; // Implicitly limits wells to 65536 x 256.
 pub const NET_VERSION: u32 = 1;
+// The layout of saved wells, the low byte of Geometry::version(). 1: 32-bit scores.
+pub const STATE_FORMAT: u32 = 1;
 
 // COMPUTATIONAL PARAMETERS
 
 pub const THREAD_NUMBER: usize = 4;
 pub const THREAD_BATCH: usize = 1024;
 pub const MULTIPLIER: f64 = 1_000_000.0;
+// Entries in the transposition table of master searches, about 56 bytes each; 0 turns it off.
+pub const TRANSPOSITION_SIZE: usize = 1 << 20;
+// Bytes master searches may use for candidate children before spilling sorted runs to disk,
+// split between the threads; 0 keeps every beam in memory. Only the candidate buffers count: the
+// selected beam, the parent tree, the transposition table and the network come on top, see spill.rs.
+pub const MASTER_BEAM_MEMORY: usize = 0;
+// Budgets for one search: wall-clock time from its start, and children evaluated; None and 0 mean
+// no budget. They are checked after each depth, and a search that runs out returns its best line so far.
+pub const MASTER_TIME_BUDGET: Option<Duration> = None;
+pub const MASTER_NODE_BUDGET: usize = 0;
+pub const TRAINING_TIME_BUDGET: Option<Duration> = None;
+pub const TRAINING_NODE_BUDGET: usize = 0;
 
 // MAX PLAY's -> turned out to be useless; don't use
 pub const MASTER_MAX_PLAY: usize = 500;
 pub const TRAINING_MAX_PLAY: usize = 100;
-
-// replay
-pub const REPLAY: bool = true;
-// directory for replay net. leave None for latest
-// example: Training/Aeon 1/Generation 0/Network 0.bin
-// pub const REPLAY_NET: Option<&str> = None;
-pub const REPLAY_NET: Option<&str> = Some("Training/Aeon 5/Generation {}/Network {}.bin");
@@ -1,19 +1,55 @@
-use crate::constants::{VERSION};
-use crate::types::{State};
+use crate::constants::{MAX_EFF_HEIGHT};
+use crate::geometry::{geometry};
+use crate::seed::{seeded_rng, DATA_STREAM};
+use crate::types::{RowT, ScoreT, State};
 use crate::types::{SearchConf};
 
 use std::path::Path;
-use std::fs;
+use std::fs::{self, File};
+use std::io::Read;
 use std::time::Instant;
+use std::mem::size_of;
 
 use rand_distr::{WeightedIndex, Distribution};
-use rand::{thread_rng, seq::SliceRandom};
+use rand::{seq::SliceRandom};
 
 use savefile::prelude::*;
 
+const STATE_BYTES: u64 = (size_of::<ScoreT>() + MAX_EFF_HEIGHT * size_of::<RowT>()) as u64;
+
+// Hashes are not saved with states, so they are recomputed as they are loaded.
+// savefile loads files of an older version as if they were current, so the version is checked first:
+// wells of another size or an older layout (16-bit scores) are refused by name.
+
+pub fn load_states(file_name: &str) -> Vec<State> {
+	let expected = geometry().version();
+	let found = saved_version(file_name);
+	if found != expected {
+		panic!(
+			"{} has savefile version {:#x}, but this build reads {:#x}: it was saved for another well size or by an older version.",
+			file_name, found, expected
+		);
+	}
+	let mut states: Vec<State> = load_file(file_name, expected)
+		.unwrap_or_else(|e| panic!("Could not load {} ({:?}).", file_name, e));
+	for state in states.iter_mut() {
+		state.rehash();
+	}
+	states
+}
+
+// The version savefile wrote after its 9-byte header and 2-byte format number.
+fn saved_version(file_name: &str) -> u32 {
+	let mut header = [0u8; 15];
+	File::open(file_name)
+		.and_then(|mut f| f.read_exact(&mut header))
+		.unwrap_or_else(|e| panic!("Could not read {} ({}).", file_name, e));
+	u32::from_le_bytes([header[11], header[12], header[13], header[14]])
+}
+
 pub fn extract_data_points(count: usize, conf: &SearchConf) -> Vec<State> {
 	// Note that this random weighting assumes the files contain only Vec<State>.
-	// Vec<State; N> takes up N*(ScoreT + 2 * EFF_HEIGHT) + 85 = 34*N + 85 bytes. 
+	// Vec<State; N> takes up N*(ScoreT + RowT * MAX_EFF_HEIGHT) + 85 bytes, 36*N + 85 with u16 rows.
 	// It may give wrong results otherwise.
 	let start = Instant::now();
 
@@ -27,15 +63,14 @@ pub fn extract_data_points(count: usize, conf: &SearchConf) -> Vec<State> {
 		depth += 1;
 		file_name = conf.move_path(depth);
 	}
-	depth -= 1;
-	let state_count: Vec<usize> = byte_counts.iter().map(|&x| ((x - 85)/34) as usize).collect();
+	let state_count: Vec<usize> = byte_counts.iter().map(|&x| ((x - 85)/STATE_BYTES) as usize).collect();
 	let len = state_count.len();
 	let sum = state_count.iter().sum::<usize>();
 	
 	println!("{} states identified from {} timesteps in {} seconds.", sum, len, start.elapsed().as_secs());
 
 	let dist = WeightedIndex::new(state_count).unwrap();
-	let mut rng = thread_rng();
+	let mut rng = seeded_rng(&[DATA_STREAM, conf.generation as u64]);
 	let mut chosen = vec![0; len];
 
 	for _ in 0..count {
@@ -45,18 +80,39 @@ pub fn extract_data_points(count: usize, conf: &SearchConf) -> Vec<State> {
 	println!("{} selections allocated in {} seconds.", count, start.elapsed().as_secs());
 
 	let mut to_return = Vec::with_capacity(count);
-	for d in 0..=depth {
+	for (d, &selections) in chosen.iter().enumerate() {
 		let file_name = conf.move_path(d);
-		let wells: Vec<State> = load_file(&file_name, VERSION).unwrap();
-		for _ in 0..chosen[d] {
+		let wells = load_states(&file_name);
+		for _ in 0..selections {
 			// We're choosing one at a time to allow repeats.
 			to_return.push(wells.choose(&mut rng).unwrap().clone());
 		}
-		println!("{} states extracted from timestep {} in {} seconds.", chosen[d], d, start.elapsed().as_secs());
+		println!("{} states extracted from timestep {} in {} seconds.", selections, d, start.elapsed().as_secs());
 	}
 	to_return.shuffle(&mut rng);
 
-	return to_return
+	to_return
 }
 
 
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::constants::STATE_FORMAT;
+
+	#[test]
+	fn wells_of_an_older_format_are_refused() {
+		let path = std::env::temp_dir().join(format!("hatetris states {}.bin", std::process::id()));
+		let path = path.to_str().unwrap();
+		let mut state = State::new();
+		state.score = 70_000;
+		save_file(path, geometry().version(), &vec![state.clone()]).unwrap();
+		assert_eq!(load_states(path), vec![state.clone()]);
+
+		save_file(path, geometry().version() - STATE_FORMAT, &vec![state]).unwrap();
+		let result = std::panic::catch_unwind(|| load_states(path));
+		fs::remove_file(path).unwrap();
+		assert!(result.is_err());
+	}
+}
@@ -1,102 +1,129 @@
-use crate::constants::{
-    EFF_HEIGHT, MAX_ROW, ROTATE_LEFT, ROTATE_RIGHT, WAVE_SIZE, WELL_HEIGHT, WELL_LINE,
-};
-use crate::masks::{EMPTY_MASKS, HEIGHT_MASKS, ROW_MASKS, SCORE_MASKS};
+use crate::geometry::geometry;
+use crate::masks::{row_chunk_index, EMPTY_MASKS, HEIGHT_MASKS, ROW_MASKS, SCORE_MASKS};
 use crate::neural::{decompose_well, forward_pass};
-use crate::pieces::{PIECE_COUNT, PIECE_LIST};
-use crate::types::{RowT, ScoreT, SearchConf, State, StateP, WaveT, WeightT, WellT};
+use crate::pieces::{piece_count, pieces, PIECE_LIST};
+use crate::seed::{hash_keys, seed_for, PIECE_STREAM};
+use crate::types::{
+    scoring, GameOver, PieceSelector, Placement, RowT, SearchConf, State, StateP, WaveT, WeightT, WellT,
+};
+use crate::transposition::TranspositionTable;
 
 use std::cmp::{max, min};
 
 use fnv::{FnvHashMap, FnvHashSet};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 
 // The 'height' of a waveform is the height of the row *below* the bottommost row of the waveform.
 
 pub fn well_slice(height: usize, well: &WellT) -> [RowT; 4] {
-    let well_slice = [
+    let eff_height = geometry().eff_height;
+    let max_row = geometry().max_row;
+    
+    [
         if (height <= 3) {
             0
-        } else if (height - 4 >= EFF_HEIGHT) {
-            MAX_ROW
+        } else if (height - 4 >= eff_height) {
+            max_row
         } else {
             well[height - 4]
         },
         if (height <= 2) {
             0
-        } else if (height - 3 >= EFF_HEIGHT) {
-            MAX_ROW
+        } else if (height - 3 >= eff_height) {
+            max_row
         } else {
             well[height - 3]
         },
         if (height <= 1) {
             0
-        } else if (height - 2 >= EFF_HEIGHT) {
-            MAX_ROW
+        } else if (height - 2 >= eff_height) {
+            max_row
         } else {
             well[height - 2]
         },
-        if (height <= 0) {
+        if (height == 0) {
             0
-        } else if (height - 1 >= EFF_HEIGHT) {
-            MAX_ROW
+        } else if (height > eff_height) {
+            max_row
         } else {
             well[height - 1]
         },
-    ];
-    return well_slice;
+    ]
 }
 
 pub fn waveform_to_wells(wave: WaveT, height: usize, p: usize, state: &State) -> Vec<State> {
-    let well = state.well;
-    let old_score = state.score;
+    let g = geometry();
 
     let mut wells = vec![];
     let mut w = wave;
-    for i in (0..WAVE_SIZE).rev() {
+    for i in (0..g.wave_size).rev() {
         if (w % 2 == 1) {
-            let slice = PIECE_LIST[p][i];
-            let mut new_well = [0; EFF_HEIGHT];
-
-            let mut score = 0;
-            for row in (0..EFF_HEIGHT).rev() {
-                let mut new_val = well[row];
-                if row <= height - 1 && row + 4 > height - 1 {
-                    new_val |= slice[3 - (height - 1 - row)];
-                }
-                if new_val == MAX_ROW {
-                    score += 1;
-                } else {
-                    new_well[row + score] = new_val;
-                }
-            }
-
-            wells.push(State {
-                well: new_well,
-                score: old_score + (score * score) as ScoreT,
-            });
+            wells.push(place_piece(i, height, p, state).0);
         };
         w >>= 1;
     }
 
-    return wells;
+    wells
 }
 
-pub fn waveform_step(w_old: WaveT, p: usize, height: usize, well: &WellT) -> WaveT {
+// Locks piece p at waveform index i and the given height, returning the new state and the lines cleared.
+
+pub fn place_piece(i: usize, height: usize, p: usize, state: &State) -> (State, usize) {
+    let g = geometry();
+    let well = state.well();
+    let slice = PIECE_LIST[p][i];
+    let mut new_state = state.clone();
+
+    // Rows only move down, so each row is still unchanged in new_state when it is written.
+    let mut score = 0;
+    for row in (0..g.eff_height).rev() {
+        let mut new_val = well[row];
+        if row < height && row + 4 > height - 1 {
+            new_val |= slice[3 - (height - 1 - row)];
+        }
+        if new_val == g.max_row {
+            score += 1;
+        } else if new_val != well[row + score] {
+            new_state.set_row(row + score, new_val);
+        }
+    }
+    // The rows cleared at the top of the well.
+    for row in 0..score {
+        new_state.set_row(row, 0);
+    }
+    new_state.score = scoring().add(state.score, score);
+
+    (new_state, score)
+}
+
+// All positions of piece p at the given height that do not overlap the well or its walls.
+
+pub fn position_mask(p: usize, height: usize, well: &WellT) -> WaveT {
     let well_slice = well_slice(height, well);
 
     let mut mask = EMPTY_MASKS[p];
     for (r, row) in well_slice.iter().enumerate() {
-        mask &= ROW_MASKS[p][*row as usize][r];
+        for chunk in 0..geometry().row_chunks {
+            mask &= ROW_MASKS[p][chunk][row_chunk_index(*row, chunk)][r];
+        }
     }
 
+    mask
+}
+
+pub fn waveform_step(w_old: WaveT, p: usize, height: usize, well: &WellT) -> WaveT {
+    let g = geometry();
+    let mask = position_mask(p, height, well);
+
     let mut w = w_old & mask;
     let mut w_new = w;
     let mut w_seen = w;
     while w_new > 0 {
         let w_right = w << 4;
         let w_left = w >> 4;
-        let w_rotate = ((w & ROTATE_LEFT) << 3) | ((w & ROTATE_RIGHT) >> 1);
+        let w_rotate = ((w & g.rotate_left) << 3) | ((w & g.rotate_right) >> 1);
         w |= w_right;
         w |= w_left;
         w |= w_rotate;
@@ -107,56 +134,102 @@ pub fn waveform_step(w_old: WaveT, p: usize, height: usize, well: &WellT) -> Wav
         // TODO: See if there's a way of doing this with only 1 intermediate variable instead of 2.
     }
 
-    return w;
+    w
 }
 
 pub fn get_well_height(well: &WellT) -> usize {
+    let eff_height = geometry().eff_height;
     let mut height = 0;
-    while height < EFF_HEIGHT {
-        if well[height as usize] != 0 {
+    while height < eff_height {
+        if well[height] != 0 {
             break;
         };
         height += 1;
     }
-    return height;
+    height
+}
+
+pub fn wave_bit(i: usize) -> WaveT {
+    1 << (geometry().wave_size - 1 - i)
+}
+
+// HATETRIS spawns every piece unrotated at the top of the well, above the line,
+// with its bounding box centred horizontally.
+// Loaded piece sets can shift the spawn column and rotation of each piece.
+// A spawn column outside the well is clamped; an invalid spawn ends the game.
+
+pub fn spawn_position(p: usize) -> (usize, usize) {
+    let piece = &pieces()[p];
+    let x = ((geometry().width - 4) / 2 + 2) as isize + piece.spawn_offset;
+    let x = x.clamp(0, geometry().width as isize) as usize;
+    (4 * x + piece.spawn_rotation, 0)
+}
+
+pub fn can_spawn(p: usize, well: &WellT) -> bool {
+    let (i, height) = spawn_position(p);
+    position_mask(p, height, well) & wave_bit(i) != 0
+}
+
+// Positions at the given height where piece p would have cells above the line.
+// Locking a piece there ends the game, so these are never legal moves.
+
+pub fn above_line_mask(p: usize, height: usize) -> WaveT {
+    match height {
+        0 => HEIGHT_MASKS[p][3],
+        1 => HEIGHT_MASKS[p][2],
+        2 => HEIGHT_MASKS[p][1],
+        3 => HEIGHT_MASKS[p][0],
+        _ => 0,
+    }
 }
 
+// Every position where piece p can come to rest, one waveform per height.
+// Positions with cells above the line are left out, so a piece with no positions at all
+// ends the game; see game_over() for the reason.
+// The search starts from the positions the piece can reach from its spawn; the rows above the
+// stack are empty, so those are the positions it can reach just above the stack.
+
 pub fn resting_waveforms(p: usize, well: &WellT) -> Vec<(WaveT, usize)> {
+    let g = geometry();
     let mut height = get_well_height(well);
 
-    let mut waves = Vec::with_capacity(EFF_HEIGHT - height + 4);
-    let mut w = EMPTY_MASKS[p];
-
-    while w > 0 && height + 1 < WELL_HEIGHT {
-        w = waveform_step(w, p, height, &well);
-        let h_mask = match height {
-            0 => HEIGHT_MASKS[p][3],
-            1 => HEIGHT_MASKS[p][2],
-            2 => HEIGHT_MASKS[p][1],
-            3 => HEIGHT_MASKS[p][0],
-            _ => 0,
-        };
+    let mut waves = Vec::with_capacity(g.eff_height - height + 4);
+    let (spawn, spawn_height) = spawn_position(p);
+    let mut w = waveform_step(wave_bit(spawn), p, spawn_height, well);
+
+    while w > 0 && height + 1 < g.well_height {
+        w = waveform_step(w, p, height, well);
+        let h_mask = above_line_mask(p, height);
 
         waves.push((w & !h_mask, height));
         height += 1;
     }
 
     // TODO: Incorporate this into the wave list generation, to minimize .push() operations and 0-value waveforms.
-    for i in 0..(waves.len() - 1) {
-        waves[i].0 &= !waves[i + 1].0;
+    for i in 1..waves.len() {
+        waves[i - 1].0 &= !waves[i].0;
     }
 
-    return waves;
+    waves
 }
 
 pub fn score_slice(wave: WaveT, height: usize, p: usize, well: &WellT) -> [WaveT; 4] {
+    let g = geometry();
     let well_slice = well_slice(height, well);
     let mut score_slice = [0; 4];
     for i in 0..4 {
-        score_slice[i] = SCORE_MASKS[p][well_slice[i] as usize][i] & wave;
+        // The floor below the well is a full row that never clears.
+        if well_slice[i] == g.max_row {
+            continue;
+        }
+        let mut mask = wave;
+        for chunk in 0..g.row_chunks {
+            mask &= SCORE_MASKS[p][chunk][row_chunk_index(well_slice[i], chunk)][i];
+        }
+        score_slice[i] = mask;
     }
 
-    return score_slice;
+    score_slice
 }
 
 pub fn scores(wave: WaveT, height: usize, p: usize, well: &WellT) -> [WaveT; 5] {
@@ -196,20 +269,21 @@ pub fn scores(wave: WaveT, height: usize, p: usize, well: &WellT) -> [WaveT; 5]
 
     score[4] = (score_slice[0] & score_slice[1] & score_slice[2] & score_slice[3]) & wave;
 
-    return score;
+    score
 }
 
 pub fn get_wave_height(wave: WaveT, wave_height: usize, p: usize, well: &WellT) -> isize {
     // We only care about the lowest possible height of all the pieces.
 
     let well_height = get_well_height(well) as isize;
+    let well_line = geometry().well_line as isize;
     if wave == 0 {
-        return -1 * (WELL_LINE as isize);
+        return -well_line;
     }
 
     let scores = scores(wave, wave_height, p, well);
 
-    let mut max_height = -1 * (WELL_LINE as isize);
+    let mut max_height = -well_line;
 
     let mut wsc = [0; 5];
     let mut total = 0;
@@ -232,38 +306,178 @@ pub fn get_wave_height(wave: WaveT, wave_height: usize, p: usize, well: &WellT)
         };
     }
 
-    return max_height;
+    max_height
 }
 
-pub fn get_legal(state: &State) -> (usize, Vec<Vec<(WaveT, usize)>>) {
-    let all_waves: Vec<Vec<(WaveT, usize)>> = (0..PIECE_COUNT)
-        .map(|p| resting_waveforms(p, &state.well))
-        .collect();
-
-    let mut legal_p = 0;
-    let mut lowest_height = WELL_HEIGHT as isize;
+// Rates each piece by the lowest stack the player can reach with it; higher ratings are better for the player.
 
-    for p in 0..PIECE_COUNT {
-        let mut piece_height = -1 * (WELL_LINE as isize);
-        for wave in &all_waves[p] {
-            let new_height = get_wave_height(wave.0, wave.1, p, &state.well);
+pub fn piece_ratings(state: &State, all_waves: &[Vec<(WaveT, usize)>]) -> Vec<isize> {
+    let mut ratings = Vec::with_capacity(piece_count());
+    for (p, waves) in all_waves.iter().enumerate() {
+        let mut piece_height = -(geometry().well_line as isize);
+        for wave in waves {
+            let new_height = get_wave_height(wave.0, wave.1, p, state.well());
             if new_height > piece_height {
                 piece_height = new_height;
             }
         }
-        if piece_height < lowest_height {
+        ratings.push(piece_height);
+    }
+    ratings
+}
+
+// Returns the piece with the lowest rating.
+// Ties go to the earliest piece in the set, as in HATETRIS.
+
+pub fn worst_piece(state: &State, all_waves: &[Vec<(WaveT, usize)>]) -> usize {
+    let ratings = piece_ratings(state, all_waves);
+
+    let mut legal_p = 0;
+    let mut lowest_height = geometry().well_height as isize;
+    for (p, &rating) in ratings.iter().enumerate() {
+        if rating < lowest_height {
             legal_p = p;
-            lowest_height = piece_height;
+            lowest_height = rating;
         }
     }
 
-    // random piece; close enough
-    (rand::thread_rng().gen_range(0..PIECE_COUNT), all_waves)
-    // (legal_p, all_waves)
+    legal_p
 }
 
-pub fn single_move(state: &State) -> Vec<State> {
-    let (piece, all_waves) = get_legal(&state);
+// All wells on the parent chain from the root of the search down to parents[index], inclusive.
+// The history keeps whole wells rather than their Zobrist hashes on purpose: a hash collision would
+// make a piece look like it loops, changing the piece HATETRIS deals, and a chain holds few enough
+// wells that hashing them in full is cheap next to generating the children.
+
+pub fn well_history(parents: &[StateP], index: usize) -> FnvHashSet<WellT> {
+    let mut history = FnvHashSet::default();
+    let mut j = index;
+    loop {
+        history.insert(parents[j].well);
+        if parents[j].depth == 0 {
+            break;
+        }
+        j = parents[j].parent_index;
+    }
+    history
+}
+
+pub fn piece_loops(
+    state: &State,
+    p: usize,
+    waves: &Vec<(WaveT, usize)>,
+    history: &FnvHashSet<WellT>,
+) -> bool {
+    for (w, h) in waves {
+        for child in waveform_to_wells(*w, *h, p, state) {
+            if history.contains(child.well()) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// HATETRIS loop prevention (https://qntm.org/loops):
+// a piece which lets the player recreate any well from the history is rated as infinitely good for the player.
+// The worst piece is therefore skipped in favour of the next-worst piece that cannot loop.
+// If every piece can loop, all are rated equally and the earliest piece in the set is chosen.
+
+pub fn worst_piece_loop(
+    state: &State,
+    all_waves: &[Vec<(WaveT, usize)>],
+    history: &FnvHashSet<WellT>,
+) -> usize {
+    let ratings = piece_ratings(state, all_waves);
+
+    let mut piece_order: Vec<(isize, usize)> = (0..piece_count()).map(|p| (ratings[p], p)).collect();
+    piece_order.sort();
+
+    for (_, p) in piece_order {
+        if !piece_loops(state, p, &all_waves[p], history) {
+            return p;
+        }
+    }
+
+    0
+}
+
+// The depth is the number of pieces placed since the start of the search; only Bag and Sequence use it.
+// The history enables loop prevention, which only changes the choice of PieceSelector::Hatetris.
+
+pub fn select_piece(
+    selector: &PieceSelector,
+    state: &State,
+    all_waves: &[Vec<(WaveT, usize)>],
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+) -> usize {
+    match selector {
+        PieceSelector::Hatetris => match history {
+            Some(history) => worst_piece_loop(state, all_waves, history),
+            None => worst_piece(state, all_waves),
+        },
+        PieceSelector::Random => {
+            let mut keys = vec![PIECE_STREAM, depth as u64];
+            keys.extend(state.well().iter().map(|&row| row as u64));
+            (seed_for(&keys) % piece_count() as u64) as usize
+        }
+        PieceSelector::Bag(seed) => {
+            let mut bag: Vec<usize> = (0..piece_count()).collect();
+            // Hashed, so that neighbouring seeds do not play the same bags shifted by one.
+            let mut rng =
+                StdRng::seed_from_u64(hash_keys(&[*seed, (depth / piece_count()) as u64]));
+            bag.shuffle(&mut rng);
+            bag[depth % piece_count()]
+        }
+        PieceSelector::Sequence(pieces) => pieces[depth % pieces.len()],
+    }
+}
+
+pub fn get_legal(
+    state: &State,
+    selector: &PieceSelector,
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+) -> (usize, Vec<Vec<(WaveT, usize)>>) {
+    // A piece that cannot spawn has no placements, which ends the game if it is chosen.
+    let all_waves: Vec<Vec<(WaveT, usize)>> = (0..piece_count())
+        .map(|p| match can_spawn(p, state.well()) {
+            true => resting_waveforms(p, state.well()),
+            false => vec![],
+        })
+        .collect();
+
+    let piece = select_piece(selector, state, &all_waves, depth, history);
+
+    (piece, all_waves)
+}
+
+// Classifies a well as alive (None) or terminal, given the piece the selector chooses for it.
+
+pub fn game_over(
+    state: &State,
+    selector: &PieceSelector,
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+) -> Option<GameOver> {
+    let (piece, all_waves) = get_legal(state, selector, depth, history);
+    if !can_spawn(piece, state.well()) {
+        return Some(GameOver::CannotSpawn { piece });
+    }
+    if all_waves[piece].iter().all(|&(w, _)| w == 0) {
+        return Some(GameOver::LockedAboveLine { piece });
+    }
+    None
+}
+
+pub fn single_move(
+    state: &State,
+    selector: &PieceSelector,
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+) -> Vec<State> {
+    let (piece, all_waves) = get_legal(state, selector, depth, history);
 
     let mut to_return = vec![];
     for (w, h) in &all_waves[piece] {
@@ -271,15 +485,58 @@ pub fn single_move(state: &State) -> Vec<State> {
         to_return.append(&mut w_list);
     }
 
-    return to_return;
+    to_return
+}
+
+// Every resting placement in a set of resting waveforms, in the same order as waveform_to_wells().
+
+pub fn wave_placements(waves: &Vec<(WaveT, usize)>, p: usize, state: &State) -> Vec<Placement> {
+    let g = geometry();
+
+    let mut placements = vec![];
+    for &(wave, height) in waves {
+        let mut w = wave;
+        for i in (0..g.wave_size).rev() {
+            if (w % 2 == 1) {
+                let (new_state, lines) = place_piece(i, height, p, state);
+                placements.push(Placement {
+                    piece: p,
+                    rotation: i % 4,
+                    x: i / 4,
+                    height,
+                    lines,
+                    state: new_state,
+                });
+            };
+            w >>= 1;
+        }
+    }
+
+    placements
+}
+
+pub fn piece_placements(p: usize, state: &State) -> Vec<Placement> {
+    wave_placements(&resting_waveforms(p, state.well()), p, state)
+}
+
+// The placement counterpart of single_move(): the same children, with the moves that produce them.
+
+pub fn legal_placements(
+    state: &State,
+    selector: &PieceSelector,
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+) -> Vec<Placement> {
+    let (piece, all_waves) = get_legal(state, selector, depth, history);
+    wave_placements(&all_waves[piece], piece, state)
 }
 
 // Gets heuristic for individual well.
 // Only to be used when batching is not appropriate.
 
 pub fn network_heuristic_individual(state: &State, weight: &WeightT, conf: &SearchConf) -> f64 {
-    let conv_list = decompose_well(&state.well);
-    let mut heuristic = forward_pass(conv_list, weight);
+    let conv_list = decompose_well(state.well());
+    let mut heuristic = forward_pass(&conv_list, weight);
     let quiescent = conf.quiescent;
 
     if !quiescent {
@@ -294,15 +551,15 @@ pub fn network_heuristic_individual(state: &State, weight: &WeightT, conf: &Sear
     // set max plays
     let mut play_len = 0;
 
-    while wells_to_evaluate.len() > 0 && play_len < conf.max_play {
+    while !wells_to_evaluate.is_empty() && play_len < conf.max_play {
         let mut queued_wells = FnvHashSet::default();
         for wev in wells_to_evaluate.iter() {
-            'piece: for p in 0..PIECE_COUNT {
+            'piece: for p in 0..piece_count() {
                 let mut tmp_queue = vec![];
 
-                let waves = resting_waveforms(p, &wev.0.well);
+                let waves = resting_waveforms(p, wev.0.well());
                 for wave in waves {
-                    let slice = score_slice(wave.0, wave.1, p, &wev.0.well);
+                    let slice = score_slice(wave.0, wave.1, p, wev.0.well());
                     let mut new_w = (0, wave.1);
                     for s in slice {
                         if s > 0 {
@@ -310,12 +567,11 @@ pub fn network_heuristic_individual(state: &State, weight: &WeightT, conf: &Sear
                         }
                     }
                     if new_w.0 > 0 {
-                        let new_wells = waveform_to_wells(new_w.0, new_w.1, p, &wev.0);
-                        for w in new_wells {
-                            if w.score - state.score != wev.1 + 1 {
+                        for pl in wave_placements(&vec![new_w], p, &wev.0) {
+                            if pl.lines != 1 {
                                 continue 'piece; // If the piece can be used to clear more than 1 line, skip the entire piece.
                             } else {
-                                tmp_queue.push(w);
+                                tmp_queue.push(pl.state);
                             }
                         }
                     }
@@ -341,59 +597,90 @@ pub fn network_heuristic_individual(state: &State, weight: &WeightT, conf: &Sear
 
         play_len += 1;
     }
-    return heuristic;
+    heuristic
 }
 
 // Used for batches; gets the children and their heuristics.
-// This is where loop prevention logic will be.
+// The history is only passed in when loop prevention is on.
+// Children found in the transposition table, if there is one, skip the network and the quiescence search,
+// and the others are added to it.
 
-pub fn network_heuristic(state: &State, weight: &WeightT, conf: &SearchConf) -> Vec<(State, f64)> {
-    let legal = single_move(state); // This will be replaced with full piece priority lookback later.
+pub fn network_heuristic(
+    state: &State,
+    weight: &WeightT,
+    conf: &SearchConf,
+    depth: usize,
+    history: Option<&FnvHashSet<WellT>>,
+    table: Option<&TranspositionTable>,
+) -> Vec<(State, f64)> {
+    let legal = single_move(state, &conf.piece_selector, depth, history);
     let quiescent = conf.quiescent;
 
     let mut heuristics: Vec<(State, f64)> = legal.iter().map(|s| (s.clone(), -1.0)).collect();
+    let mut to_evaluate = vec![];
     for i in 0..legal.len() {
-        let conv_list = decompose_well(&heuristics[i].0.well);
-        heuristics[i].1 = forward_pass(conv_list, weight);
+        match table.and_then(|t| t.get(&legal[i])) {
+            Some(h) => heuristics[i].1 = h,
+            None => to_evaluate.push(i),
+        }
+    }
+    for &i in &to_evaluate {
+        let conv_list = decompose_well(heuristics[i].0.well());
+        heuristics[i].1 = forward_pass(&conv_list, weight);
     }
 
-    if !quiescent {
-        return heuristics;
+    if quiescent {
+        quiescent_heuristics(&mut heuristics, &to_evaluate, weight, conf);
     }
 
+    if let Some(t) = table {
+        for &i in &to_evaluate {
+            t.insert(&heuristics[i].0, heuristics[i].1);
+        }
+    }
+    heuristics
+}
+
+// Raises the heuristics of the wells at the given indices to the best heuristic reachable from them
+// by clearing single lines, as network_heuristic_individual() does for one well.
+
+fn quiescent_heuristics(
+    heuristics: &mut [(State, f64)],
+    indices: &[usize],
+    weight: &WeightT,
+    conf: &SearchConf,
+) {
     let mut wells_to_evaluate = FnvHashMap::default();
-    for i in 0..legal.len() {
-        if !wells_to_evaluate.contains_key(&(legal[i].clone(), 0)) {
-            wells_to_evaluate.insert((legal[i].clone(), 0), vec![i]);
+    for &i in indices {
+        if !wells_to_evaluate.contains_key(&(heuristics[i].0.clone(), 0)) {
+            wells_to_evaluate.insert((heuristics[i].0.clone(), 0), vec![i]);
         } else {
             let mut affected_wells = wells_to_evaluate
-                .get(&(legal[i].clone(), 0))
+                .get(&(heuristics[i].0.clone(), 0))
                 .unwrap()
                 .clone();
             affected_wells.push(i);
-            wells_to_evaluate.insert((legal[i].clone(), 0), affected_wells);
+            wells_to_evaluate.insert((heuristics[i].0.clone(), 0), affected_wells);
         }
     }
 
     let mut heuristic_map = FnvHashMap::default();
-    for i in 0..legal.len() {
-        heuristic_map.insert(heuristics[i].0.clone(), heuristics[i].1.clone());
+    for &i in indices {
+        heuristic_map.insert(heuristics[i].0.clone(), heuristics[i].1);
     }
 
     // max play length
     let mut play_len = 0;
 
-    while wells_to_evaluate.len() > 0 && play_len < conf.max_play {
+    while !wells_to_evaluate.is_empty() && play_len < conf.max_play {
         let mut queued_wells = FnvHashMap::default();
         for wev in wells_to_evaluate.iter() {
-            let prev_score = legal[wev.1[0]].score;
-
-            'piece: for p in 0..PIECE_COUNT {
+            'piece: for p in 0..piece_count() {
                 let mut tmp_queue = vec![];
 
-                let waves = resting_waveforms(p, &wev.0 .0.well);
+                let waves = resting_waveforms(p, wev.0 .0.well());
                 for wave in waves {
-                    let slice = score_slice(wave.0, wave.1, p, &wev.0 .0.well);
+                    let slice = score_slice(wave.0, wave.1, p, wev.0 .0.well());
                     let mut new_w = (0, wave.1);
                     for s in slice {
                         if s > 0 {
@@ -401,12 +688,11 @@ pub fn network_heuristic(state: &State, weight: &WeightT, conf: &SearchConf) ->
                         }
                     }
                     if new_w.0 > 0 {
-                        let new_wells = waveform_to_wells(new_w.0, new_w.1, p, &wev.0 .0);
-                        for w in new_wells {
-                            if w.score - prev_score != wev.0 .1 + 1 {
+                        for pl in wave_placements(&vec![new_w], p, &wev.0 .0) {
+                            if pl.lines != 1 {
                                 continue 'piece; // If the piece can be used to clear more than 1 line, skip the entire piece.
                             } else {
-                                tmp_queue.push(w);
+                                tmp_queue.push(pl.state);
                             }
                         }
                     }
@@ -447,182 +733,322 @@ pub fn network_heuristic(state: &State, weight: &WeightT, conf: &SearchConf) ->
 
         play_len += 1;
     }
-
-    return heuristics;
 }
 
-pub fn network_heuristic_loop(
-    state: &State,
-    p: usize,
-    parents: &Vec<(usize, StateP)>,
-    weight: &WeightT,
-    conf: &SearchConf,
-) -> (Vec<(State, f64)>, Vec<Vec<State>>) {
-    let all_waves: Vec<Vec<(WaveT, usize)>> = (0..PIECE_COUNT)
-        .map(|p| resting_waveforms(p, &state.well))
-        .collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::MAX_EFF_HEIGHT;
+    use crate::types::ScoringRule;
 
-    let mut piece_order = vec![];
+    use std::{env, fs, process};
 
-    for p in 0..PIECE_COUNT {
-        let mut piece_height = -1 * (WELL_LINE as isize);
-        for wave in &all_waves[p] {
-            let new_height = get_wave_height(wave.0, wave.1, p, &state.well);
-            if new_height > piece_height {
-                piece_height = new_height;
-            }
+    const O: usize = 2;
+
+    // Builds a well from its bottom rows, listed top to bottom.
+    fn well_from_rows(rows: &[RowT]) -> WellT {
+        let eff_height = geometry().eff_height;
+        let mut well = [0; MAX_EFF_HEIGHT];
+        well[eff_height - rows.len()..eff_height].copy_from_slice(rows);
+        well
+    }
+
+    fn all_waves(state: &State) -> Vec<Vec<(WaveT, usize)>> {
+        (0..piece_count())
+            .map(|p| resting_waveforms(p, state.well()))
+            .collect()
+    }
+
+    fn children(state: &State, p: usize) -> Vec<State> {
+        let mut to_return = vec![];
+        for (w, h) in resting_waveforms(p, state.well()) {
+            to_return.append(&mut waveform_to_wells(w, h, p, state));
         }
-        piece_order.push((piece_height, p));
+        to_return
     }
-    piece_order.sort();
 
-    for (_, legal_p) in piece_order {
-        let mut legal = vec![];
-        for (w, h) in &all_waves[legal_p] {
-            let mut w_list = waveform_to_wells(*w, *h, legal_p, state);
-            legal.append(&mut w_list);
-        }
-
-        let mut heuristics: Vec<(State, f64)> = legal.iter().map(|s| (s.clone(), -1.0)).collect();
-        for i in 0..legal.len() {
-            let conv_list = decompose_well(&heuristics[i].0.well);
-            heuristics[i].1 = forward_pass(conv_list, weight);
-        }
-
-        let mut wells_to_evaluate = FnvHashMap::default();
-        for i in 0..legal.len() {
-            if !wells_to_evaluate.contains_key(&(legal[i].clone(), 0)) {
-                wells_to_evaluate.insert((legal[i].clone(), 0), vec![i]);
-            } else {
-                let mut affected_wells = wells_to_evaluate
-                    .get(&(legal[i].clone(), 0))
-                    .unwrap()
-                    .clone();
-                affected_wells.push(i);
-                wells_to_evaluate.insert((legal[i].clone(), 0), affected_wells);
-            }
+    // Five O pieces dropped left to right fill two rows, which clear back to the empty well.
+    fn o_loop() -> Vec<State> {
+        let mut wells = vec![];
+        for filled in (0..geometry().width).step_by(2) {
+            let max_row = geometry().max_row;
+            let row = (max_row << (geometry().width - filled)) & max_row;
+            wells.push(State::from_well(well_from_rows(&[row, row]), 0));
         }
+        wells
+    }
 
-        let mut heuristic_map = FnvHashMap::default();
-        for i in 0..legal.len() {
-            heuristic_map.insert(heuristics[i].0.clone(), heuristics[i].1.clone());
+    fn parent_chain(wells: &[State]) -> Vec<StateP> {
+        wells
+            .iter()
+            .enumerate()
+            .map(|(i, s)| StateP {
+                well: *s.well(),
+                score: s.score,
+                hash: s.hash(),
+                heuristic: 0.0,
+                min_prev_heuristic: 0.0,
+                depth: i,
+                parent_index: if i == 0 { usize::MAX } else { i - 1 },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn o_sequence_loops_back_to_empty_well() {
+        let wells = o_loop();
+        for i in 1..wells.len() {
+            let next = children(&wells[i - 1], O);
+            assert!(next.iter().any(|c| c.well() == wells[i].well()));
         }
 
-        let mut play_len = 0;
+        let last = wells.last().unwrap();
+        assert!(children(last, O)
+            .iter()
+            .any(|c| *c.well() == [0; MAX_EFF_HEIGHT]));
+    }
 
-        while wells_to_evaluate.len() > 0 && play_len < conf.max_play {
-            let mut queued_wells = FnvHashMap::default();
-            for wev in wells_to_evaluate.iter() {
-                let prev_score = legal[wev.1[0]].score;
+    #[test]
+    fn well_history_follows_parent_chain() {
+        let wells = o_loop();
+        let parents = parent_chain(&wells);
+        let history = well_history(&parents, parents.len() - 1);
 
-                'piece: for p in 0..PIECE_COUNT {
-                    let mut tmp_queue = vec![];
+        assert_eq!(history.len(), wells.len());
+        for w in &wells {
+            assert!(history.contains(w.well()));
+        }
+        assert_eq!(well_history(&parents, 0).len(), 1);
+    }
 
-                    let waves = resting_waveforms(p, &wev.0 .0.well);
-                    for wave in waves {
-                        let slice = score_slice(wave.0, wave.1, p, &wev.0 .0.well);
-                        let mut new_w = (0, wave.1);
-                        for s in slice {
-                            if s > 0 {
-                                new_w.0 |= wave.0 & s;
-                            }
-                        }
-                        if new_w.0 > 0 {
-                            let new_wells = waveform_to_wells(new_w.0, new_w.1, p, &wev.0 .0);
-                            for w in new_wells {
-                                if w.score - prev_score != wev.0 .1 + 1 {
-                                    continue 'piece; // If the piece can be used to clear more than 1 line, skip the entire piece.
-                                } else {
-                                    tmp_queue.push(w);
-                                }
-                            }
-                        }
-                    }
+    #[test]
+    fn looping_piece_is_detected() {
+        let wells = o_loop();
+        let parents = parent_chain(&wells);
+        let history = well_history(&parents, parents.len() - 1);
+        let last = wells.last().unwrap();
+        let waves = all_waves(last);
 
-                    for well in tmp_queue {
-                        if !queued_wells.contains_key(&(well.clone(), wev.0 .1 + 1)) {
-                            queued_wells.insert((well.clone(), wev.0 .1 + 1), wev.1.clone());
+        assert!(piece_loops(last, O, &waves[O], &history));
+        assert!(!piece_loops(last, O, &waves[O], &FnvHashSet::default()));
+    }
 
-                            let mut tmp_conf = conf.clone();
-                            tmp_conf.quiescent = false;
+    #[test]
+    fn selected_piece_never_recreates_history() {
+        let wells = o_loop();
+        let parents = parent_chain(&wells);
+        let history = well_history(&parents, parents.len() - 1);
+
+        for (i, well) in wells.iter().enumerate() {
+            let waves = all_waves(well);
+            let p = select_piece(
+                &PieceSelector::Hatetris,
+                well,
+                &waves,
+                i,
+                Some(&history),
+            );
+            for child in children(well, p) {
+                assert!(!history.contains(child.well()));
+            }
+        }
+    }
 
-                            let h = network_heuristic_individual(&well, weight, &tmp_conf);
-                            for &id in wev.1 {
-                                heuristics[id].1 = heuristics[id].1.max(h);
-                            }
-                            heuristic_map.insert(well, h);
-                        } else {
-                            let mut affected_wells = queued_wells
-                                .get(&(well.clone(), wev.0 .1 + 1))
-                                .unwrap()
-                                .clone();
-                            let mut to_update = wev.1.clone();
-                            affected_wells.append(&mut to_update);
-                            queued_wells.insert((well.clone(), wev.0 .1 + 1), affected_wells);
-                            let h = *heuristic_map.get(&well).unwrap();
-                            for &id in wev.1 {
-                                heuristics[id].1 = heuristics[id].1.max(h);
-                            }
-                        }
-                    }
+    #[test]
+    fn worst_piece_falls_through_to_next_worst() {
+        for state in o_loop() {
+            let waves = all_waves(&state);
+            let worst = worst_piece(&state, &waves);
 
-                    break 'piece;
+            let mut history = FnvHashSet::default();
+            history.insert(*children(&state, worst)[0].well());
+
+            let chosen = worst_piece_loop(&state, &waves, &history);
+            assert_ne!(chosen, worst);
+
+            // The fallback must be the lowest rated piece among those that cannot loop.
+            let ratings = piece_ratings(&state, &waves);
+            for p in 0..piece_count() {
+                if p != worst && (ratings[p], p) < (ratings[chosen], chosen) {
+                    assert!(piece_loops(&state, p, &waves[p], &history));
                 }
             }
-            wells_to_evaluate.clear();
-            wells_to_evaluate = queued_wells;
-
-            play_len += 1;
-        }
-
-        // LOOP DETECTION
-
-        let mut max_heuristic: f64 = -1.0;
-        for (_s, h) in &heuristics {
-            max_heuristic = max_heuristic.max(*h);
-        }
-
-        let mut has_loop = false;
-        let mut j = p;
-        let mut d = parents[j].1.depth;
-        'outer: while d > 0 {
-            d = parents[j].1.depth;
-            let min_prev = parents[j].1.min_prev_heuristic;
-            let curr = parents[j].1.heuristic;
-            if min_prev > max_heuristic || curr > max_heuristic {
-                break 'outer;
-            } else {
-                // If this is slow, then you have to do a binary search on the sorted heuristic values.
-                // and you can't use the default rust binary search, so we probably have to roll our own
-                // or use a crate I guess.
-
-                let parent_state = parents[j].1.convert_state();
-                for (s, h) in heuristics.iter() {
-                    if *h == curr && parent_state == *s {
-                        has_loop = true;
-                        break 'outer;
+        }
+    }
+
+    #[test]
+    fn without_loops_selection_matches_worst_piece() {
+        for state in o_loop() {
+            let waves = all_waves(&state);
+            let history = FnvHashSet::default();
+            assert_eq!(
+                worst_piece_loop(&state, &waves, &history),
+                worst_piece(&state, &waves)
+            );
+        }
+    }
+
+    #[test]
+    fn all_pieces_looping_falls_back_to_first_piece() {
+        let state = o_loop()[1].clone();
+        let waves = all_waves(&state);
+
+        let mut history = FnvHashSet::default();
+        for p in 0..piece_count() {
+            for child in children(&state, p) {
+                history.insert(*child.well());
+            }
+        }
+
+        assert_eq!(worst_piece_loop(&state, &waves, &history), 0);
+    }
+
+    // The chunked score masks must count the same lines as placing each piece row by row.
+    #[test]
+    fn score_masks_match_line_clears() {
+        let g = geometry();
+        let holes = [0, 1, g.width / 2, g.width - 1];
+        let rows: Vec<RowT> = holes.iter().map(|&x| g.max_row & !((1 as RowT) << x)).collect();
+        let state = State::from_well(well_from_rows(&rows), 0);
+
+        for p in 0..piece_count() {
+            for (wave, h) in resting_waveforms(p, state.well()) {
+                // waveform_to_wells() lists the children from the lowest bit up.
+                let mut bits = vec![];
+                for i in 0..g.wave_size {
+                    let bit = (1 as WaveT) << i;
+                    if wave & bit != 0 {
+                        bits.push(bit);
                     }
                 }
+                let children = waveform_to_wells(wave, h, p, &state);
+                for (bit, child) in bits.iter().zip(children.iter()) {
+                    let lines = scores(*bit, h, p, state.well())
+                        .iter()
+                        .position(|s| s & bit != 0)
+                        .unwrap();
+                    assert_eq!(child.score, scoring().points(lines));
+                }
             }
+        }
+    }
 
-            j = parents[j].1.parent_index;
+    #[test]
+    fn placements_match_single_move() {
+        let state = o_loop()[1].clone();
+        for selector in [PieceSelector::Hatetris, PieceSelector::Sequence(vec![3])] {
+            let placements = legal_placements(&state, &selector, 0, None);
+            let children: Vec<State> = placements.iter().map(|pl| pl.state.clone()).collect();
+            assert_eq!(children, single_move(&state, &selector, 0, None));
         }
+    }
 
-        if has_loop {
-            let mut j = parents.len() - 1;
-            let mut d = parents[j].1.depth;
-            let mut loop_list = Vec::with_capacity(d + 1);
-            while d > 0 {
-                d = parents[j].1.depth;
-                loop_list.push(parents[j].1.convert_state());
-                j = parents[j].1.parent_index;
-            }
+    #[test]
+    fn placements_describe_the_move() {
+        let g = geometry();
+        let placements = piece_placements(O, &State::new());
+        assert_eq!(placements.len(), 4 * (g.width - 1));
+
+        for pl in placements {
+            assert_eq!(pl.piece, O);
+            // The O fills rows 1 and 2 and columns 1 and 2 of its box,
+            // which spans rows height - 4 to height - 1 and starts at column x - 2.
+            assert_eq!(pl.height, g.eff_height + 1);
+            assert_eq!(pl.lines, 0);
+            let row = 0b11 << (g.width - 1 - pl.x);
+            assert_eq!(*pl.state.well(), well_from_rows(&[row, row]));
+        }
 
-            return (heuristics, vec![loop_list]);
-        } else {
-            return (heuristics, vec![]);
+        let state = o_loop()[o_loop().len() - 1].clone();
+        let clears: Vec<Placement> = piece_placements(O, &state)
+            .into_iter()
+            .filter(|pl| pl.lines > 0)
+            .collect();
+        assert_eq!(clears.len(), 4);
+        for pl in clears {
+            assert_eq!(pl.lines, 2);
+            assert_eq!(*pl.state.well(), *State::new().well());
+            assert_eq!(pl.state.score, scoring().points(2));
         }
     }
 
-    return (vec![], vec![]);
+    // Every row is full except for column 0, so only an upright I fits below the line.
+    fn shaft() -> State {
+        let g = geometry();
+        let rows = vec![g.max_row >> 1; g.eff_height];
+        State::from_well(well_from_rows(&rows), 0)
+    }
+
+    #[test]
+    fn game_over_reports_the_reason() {
+        assert_eq!(game_over(&State::new(), &PieceSelector::Hatetris, 0, None), None);
+
+        let state = shaft();
+        assert_eq!(
+            game_over(&state, &PieceSelector::Sequence(vec![O]), 0, None),
+            Some(GameOver::LockedAboveLine { piece: O })
+        );
+        assert_eq!(game_over(&state, &PieceSelector::Sequence(vec![3]), 0, None), None);
+        assert!(single_move(&state, &PieceSelector::Sequence(vec![O]), 0, None).is_empty());
+    }
+
+    #[test]
+    fn neighbouring_bag_seeds_differ() {
+        let bags = |seed: u64, start: usize| -> Vec<usize> {
+            (start..start + 4 * piece_count())
+                .map(|d| select_piece(&PieceSelector::Bag(seed), &State::new(), &[], d, None))
+                .collect()
+        };
+        assert_eq!(bags(7, 0), bags(7, 0));
+        assert_ne!(bags(7, piece_count()), bags(8, 0));
+    }
+
+    #[test]
+    fn empty_sequences_are_rejected() {
+        assert!(PieceSelector::parse("seq:").is_err());
+        assert_eq!(PieceSelector::parse("seq:0,1"), Ok(PieceSelector::Sequence(vec![0, 1])));
+    }
+
+    #[test]
+    fn selector_names_parse_back() {
+        for text in ["hatetris", "random", "bag:7", "seq:0,1"] {
+            assert_eq!(PieceSelector::parse(text).unwrap().name(), text);
+        }
+    }
+
+    // The piece set is read once per process, so this test runs itself again with PIECE_SET
+    // naming a set whose only piece spawns past the right wall.
+    #[test]
+    fn pieces_spawning_outside_the_well_cannot_spawn() {
+        if env::var("PIECE_SET").is_ok() {
+            assert_eq!(piece_count(), 1);
+            assert_eq!(
+                game_over(&State::new(), &PieceSelector::Hatetris, 0, None),
+                Some(GameOver::CannotSpawn { piece: 0 })
+            );
+            assert!(single_move(&State::new(), &PieceSelector::Hatetris, 0, None).is_empty());
+            return;
+        }
+
+        let path = env::temp_dir().join(format!("hatetris cannot spawn {}.txt", process::id()));
+        let piece_set = "piece O 9 0\n\
+                         .... .... .... ....\n\
+                         .##. .##. .##. .##.\n\
+                         .##. .##. .##. .##.\n\
+                         .... .... .... ....\n";
+        fs::write(&path, piece_set).unwrap();
+        let status = process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "emulator::tests::pieces_spawning_outside_the_well_cannot_spawn"])
+            .env("PIECE_SET", &path)
+            .status()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn classic_scores_do_not_cap_at_16_bits() {
+        assert_eq!(ScoringRule::Classic.add(65_000, 4), 66_200);
+        assert_eq!(ScoringRule::parse(ScoringRule::Classic.name()), Ok(ScoringRule::Classic));
+    }
 }
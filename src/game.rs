@@ -0,0 +1,205 @@
+// A mutable game, for depth first tools.
+
+// Game applies placements to a single well in place and can undo them, line clears included,
+// so a depth first search only touches the rows a piece covers instead of copying a State per node:
+//		for (i, height) in game.moves(p) {
+//			game.make(p, i, height);
+//			search(&mut game);
+//			game.unmake();
+//		}
+// The results are the same as place_piece(), which the breadth first searches use.
+
+use crate::emulator::{resting_waveforms, wave_bit};
+use crate::geometry::geometry;
+use crate::pieces::PIECE_LIST;
+use crate::types::{scoring, ScoreT, State};
+
+// What make() changed: the piece position, the rows it cleared (top to bottom) and the old score.
+#[derive(Clone, Debug)]
+struct Undo {
+    piece: usize,
+    index: usize,
+    height: usize,
+    cleared: [usize; 4],
+    lines: usize,
+    score: ScoreT,
+}
+
+#[derive(Clone, Debug)]
+pub struct Game {
+    state: State,
+    undo: Vec<Undo>,
+}
+
+impl Game {
+    pub fn new(state: State) -> Game {
+        Game {
+            state,
+            undo: vec![],
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // Number of placements that can be undone.
+    pub fn ply(&self) -> usize {
+        self.undo.len()
+    }
+
+    // Every resting position (i, height) of piece p, without building the resulting wells.
+    pub fn moves(&self, p: usize) -> Vec<(usize, usize)> {
+        let g = geometry();
+        let mut moves = vec![];
+        for (w, height) in resting_waveforms(p, self.state.well()) {
+            for i in 0..g.wave_size {
+                if w & wave_bit(i) != 0 {
+                    moves.push((i, height));
+                }
+            }
+        }
+        moves
+    }
+
+    // Locks piece p at waveform index i and the given height, returning the lines cleared.
+    // Cells above the line are dropped, as in place_piece().
+    pub fn make(&mut self, p: usize, i: usize, height: usize) -> usize {
+        let g = geometry();
+        let state = &mut self.state;
+        let slice = PIECE_LIST[p][i];
+
+        let mut cleared = [0; 4];
+        let mut lines = 0;
+        for (r, &piece_row) in slice.iter().enumerate() {
+            if height + r < 4 || height + r - 4 >= g.eff_height {
+                continue;
+            }
+            let row = height + r - 4;
+            let value = state.well()[row] | piece_row;
+            state.set_row(row, value);
+            if value == g.max_row {
+                cleared[lines] = row;
+                lines += 1;
+            }
+        }
+
+        // Shift the rows above each cleared row down, from the lowest cleared row up.
+        if lines > 0 {
+            let mut write = cleared[lines - 1];
+            for read in (0..cleared[lines - 1]).rev() {
+                if cleared[..lines].contains(&read) {
+                    continue;
+                }
+                state.set_row(write, state.well()[read]);
+                write -= 1;
+            }
+            for row in 0..lines {
+                state.set_row(row, 0);
+            }
+        }
+
+        self.undo.push(Undo {
+            piece: p,
+            index: i,
+            height,
+            cleared,
+            lines,
+            score: state.score,
+        });
+        state.score = scoring().add(state.score, lines);
+        lines
+    }
+
+    // Undoes the last make().
+    pub fn unmake(&mut self) {
+        let g = geometry();
+        let undo = self.undo.pop().expect("Nothing to undo.");
+        let state = &mut self.state;
+
+        // Each row that survived the clear moved down by the number of cleared rows below it.
+        // Moving rows back up from the top never overwrites a row that has yet to move.
+        if undo.lines > 0 {
+            let cleared = &undo.cleared[..undo.lines];
+            for row in 0..=cleared[undo.lines - 1] {
+                if cleared.contains(&row) {
+                    state.set_row(row, g.max_row);
+                } else {
+                    let below = cleared.iter().filter(|&&c| c > row).count();
+                    state.set_row(row, state.well()[row + below]);
+                }
+            }
+        }
+
+        let slice = PIECE_LIST[undo.piece][undo.index];
+        for (r, &piece_row) in slice.iter().enumerate() {
+            if undo.height + r < 4 || undo.height + r - 4 >= g.eff_height {
+                continue;
+            }
+            let row = undo.height + r - 4;
+            state.set_row(row, state.well()[row] & !piece_row);
+        }
+        state.score = undo.score;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::place_piece;
+    use crate::pieces::piece_count;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_state(rng: &mut StdRng) -> State {
+        let g = geometry();
+        let mut state = State::new();
+        for row in rng.gen_range(0..g.eff_height)..g.eff_height {
+            state.set_row(row, rng.gen_range(0..g.max_row));
+        }
+        state
+    }
+
+    #[test]
+    fn make_matches_place_piece_and_unmake_restores() {
+        let mut rng = StdRng::seed_from_u64(13);
+        for _ in 0..200 {
+            let start = random_state(&mut rng);
+            let mut game = Game::new(start.clone());
+            for p in 0..piece_count() {
+                for (i, height) in game.moves(p) {
+                    let (expected, lines) = place_piece(i, height, p, &start);
+                    assert_eq!(game.make(p, i, height), lines);
+                    assert_eq!(*game.state(), expected);
+                    game.unmake();
+                    assert_eq!(*game.state(), start);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nested_moves_unwind() {
+        let mut rng = StdRng::seed_from_u64(14);
+        let start = random_state(&mut rng);
+        let mut game = Game::new(start.clone());
+
+        let mut path = vec![];
+        for d in 0..6 {
+            let moves = game.moves(d % piece_count());
+            if moves.is_empty() {
+                break;
+            }
+            let (i, height) = moves[rng.gen_range(0..moves.len())];
+            path.push(game.state().clone());
+            game.make(d % piece_count(), i, height);
+        }
+        assert_eq!(game.ply(), path.len());
+
+        while let Some(state) = path.pop() {
+            game.unmake();
+            assert_eq!(*game.state(), state);
+        }
+    }
+}
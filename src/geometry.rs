@@ -0,0 +1,133 @@
+// Well geometry, chosen once at startup.
+
+// The dimensions are read from the environment variables WELL_WIDTH, WELL_HEIGHT and WELL_LINE
+// the first time geometry() is called, falling back to the defaults in constants.rs:
+//		WELL_WIDTH=8 cargo run --release
+// Everything derived from the dimensions (waveform layout, masks, network size) is computed here.
+
+use crate::constants::{
+    CONVOLUTIONS, DEFAULT_WELL_HEIGHT, DEFAULT_WELL_LINE, DEFAULT_WIDTH, HIDDEN, MAX_EFF_HEIGHT,
+    STATE_FORMAT,
+};
+use crate::types::{RowT, WaveT};
+
+use std::env;
+use std::sync::OnceLock;
+
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    pub width: usize,
+    pub well_height: usize,
+    pub well_line: usize,
+    pub eff_height: usize,
+    pub wave_size: usize,
+    pub max_row: RowT,
+
+    // Row masks are indexed by chunks of at most 16 columns, so the tables stay small for wide wells.
+    pub row_chunk: usize,
+    pub row_chunks: usize,
+
+    // ROTATE_LEFT:   0b000100010001...
+    // ROTATE_RIGHT:  0b111011101110...
+    pub wave_offset: WaveT,
+    pub rotate_left: WaveT,
+    pub rotate_right: WaveT,
+
+    pub convolutions: [(usize, usize); 2],
+    pub conv_pow: [usize; 2],
+    pub conv_count: usize,
+    pub all_conv: usize,
+    pub weight_count: usize,
+}
+
+impl Geometry {
+    pub fn new(width: usize, well_height: usize, well_line: usize) -> Geometry {
+        assert!(width >= 4, "Wells must be at least 4 wide to spawn pieces.");
+        assert!(
+            width + 2 <= RowT::BITS as usize && 4 * width + 4 <= WaveT::BITS as usize,
+            "Width {} does not fit RowT and WaveT; wider wells need --features wide.",
+            width
+        );
+        assert!(
+            well_line >= 4,
+            "The line must leave 4 rows above it to spawn pieces."
+        );
+        assert!(
+            well_height >= well_line + 4 && well_height - well_line <= MAX_EFF_HEIGHT,
+            "The well must have between 4 and {} rows below the line.",
+            MAX_EFF_HEIGHT
+        );
+
+        let eff_height = well_height - well_line;
+
+        // WAVE_OFFSET used to be (16^(w+1) - 1)/15, but the w+1 limited well width too much.
+        // Now it is 16 * (16^w - 1)/15 + 1
+        let wave_offset = 1 + 16 * ((16 as WaveT).pow(width as u32) - 1) / 15;
+
+        let row_chunk = width.min(16);
+
+        let convolutions = CONVOLUTIONS.map(|(m, n)| (m.min(eff_height), n.min(width)));
+        let conv_pow = convolutions.map(|(m, n)| 2_usize.pow((m * n) as u32));
+
+        let mut conv_count = 0;
+        let mut all_conv = 0;
+        for c in 0..convolutions.len() {
+            let (m, n) = convolutions[c];
+            let positions = (eff_height - m + 1) * (width - n + 1);
+            conv_count += positions;
+            all_conv += conv_pow[c] * positions;
+        }
+
+        Geometry {
+            width,
+            well_height,
+            well_line,
+            eff_height,
+            wave_size: 4 * width + 4,
+            max_row: ((1 as RowT) << width) - 1,
+            row_chunk,
+            row_chunks: width.div_ceil(row_chunk),
+            wave_offset,
+            rotate_left: wave_offset,
+            rotate_right: wave_offset * 14,
+            convolutions,
+            conv_pow,
+            conv_count,
+            all_conv,
+            weight_count: HIDDEN * all_conv,
+        }
+    }
+
+    // The savefile version of saved wells, so that wells of another size or layout do not load.
+    // Implicitly limits wells to 65536 x 256 and STATE_FORMAT to 256 layouts.
+    pub fn version(&self) -> u32 {
+        ((self.well_height << 16) + (self.width << 8)) as u32 + STATE_FORMAT
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.width == DEFAULT_WIDTH
+            && self.well_height == DEFAULT_WELL_HEIGHT
+            && self.well_line == DEFAULT_WELL_LINE
+    }
+}
+
+static GEOMETRY: OnceLock<Geometry> = OnceLock::new();
+
+fn env_or(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number, not {}.", name, value)),
+        Err(_) => default,
+    }
+}
+
+pub fn geometry() -> &'static Geometry {
+    GEOMETRY.get_or_init(|| {
+        Geometry::new(
+            env_or("WELL_WIDTH", DEFAULT_WIDTH),
+            env_or("WELL_HEIGHT", DEFAULT_WELL_HEIGHT),
+            env_or("WELL_LINE", DEFAULT_WELL_LINE),
+        )
+    })
+}
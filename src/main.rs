@@ -1,18 +1,39 @@
 #![allow(unused_parens)]
 
+pub mod bench;
+pub mod compare;
 pub mod constants;
 pub mod database;
 pub mod emulator;
+pub mod game;
+pub mod geometry;
 pub mod masks;
 pub mod neural;
+pub mod perft;
 pub mod pieces;
+pub mod playback;
+pub mod pool;
+pub mod replay;
 pub mod searches;
+pub mod seed;
+pub mod spill;
+pub mod topk;
+pub mod transposition;
+pub mod tree;
 pub mod types;
+pub mod zobrist;
 
-use crate::constants::{ALL_CONV, EFF_HEIGHT, HIDDEN, WIDTH, WEIGHT_COUNT};
-use crate::masks::{EMPTY_MASKS, ROW_MASKS, HEIGHT_MASKS, SCORE_MASKS, SURFACE_LINE_ARRAY};
+use crate::bench::{topk_bench_command};
+use crate::compare::{compare_command};
+use crate::constants::{HIDDEN};
+use crate::geometry::{geometry};
+use crate::masks::{EMPTY_MASKS, ROW_MASKS, HEIGHT_MASKS, SCORE_MASKS};
 use crate::neural::{training_cycle};
-use crate::pieces::{PIECE_LIST};
+use crate::perft::{perft_command};
+use crate::pieces::{piece_set_name, pieces, PIECE_LIST};
+use crate::playback::{replay_command, score_command};
+use crate::replay::{verify_replay};
+use crate::types::{scoring};
 
 extern crate savefile;
 
@@ -22,12 +43,27 @@ fn main() {
 	println!("HEIGHT_MASKS created with {} elements.", HEIGHT_MASKS.len());
 	println!("SCORE_MASKS created with {} elements.", SCORE_MASKS.len());
 	println!("PIECE_LIST created with {} elements.", PIECE_LIST.len());
-	println!("SURFACE_LINE_ARRAY created with {} elements.", SURFACE_LINE_ARRAY.len());
 
-	println!("");
-	println!("Well height: {}, well width: {}", EFF_HEIGHT, WIDTH);
-	println!("Neural network contains {} convolutional nodes and {} hidden nodes for {} total weights.", ALL_CONV, HIDDEN, WEIGHT_COUNT);
-	println!("");
+	println!();
+	let g = geometry();
+	println!("Well height: {}, well width: {}", g.eff_height, g.width);
+	let names: Vec<&str> = pieces().iter().map(|piece| piece.name.as_str()).collect();
+	println!("Pieces: {} ({})", names.join(" "), piece_set_name().unwrap_or(String::from("hatetris")));
+	println!("Scoring: {:?}", scoring());
+	println!("Neural network contains {} convolutional nodes and {} hidden nodes for {} total weights.", g.all_conv, HIDDEN, g.weight_count);
+	println!();
 
-	training_cycle();
+	let args: Vec<String> = std::env::args().collect();
+	match args.get(1).map(|a| a.as_str()) {
+		Some("verify") => verify_replay(
+			args.get(2).expect("Usage: verify <replay code or file> [move]"),
+			args.get(3).map(|m| m.parse().expect("The move must be a number.")),
+		),
+		Some("perft") => perft_command(&args[2..]),
+		Some("replay") => replay_command(&args[2..]),
+		Some("score") => score_command(&args[2..]),
+		Some("compare") => compare_command(&args[2..]),
+		Some("bench-topk") => topk_bench_command(&args[2..]),
+		_ => training_cycle(),
+	}
 }
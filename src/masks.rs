@@ -1,24 +1,25 @@
 use lazy_static::lazy_static;
 
-use crate::constants::{MAX_ROW, WAVE_SIZE};
-use crate::pieces::{PIECE_COUNT, PIECE_LIST};
-use crate::types::{WaveT};
+use crate::geometry::{geometry};
+use crate::pieces::{piece_count, PIECE_LIST};
+use crate::types::{RowT, WaveT};
 
 // Masks
 // All masks have as many significant bits as waveforms do.
 // All masks are 0, for invalid positions within a waveform and 1 for valid positions.
 
 lazy_static!{
-	pub static ref EMPTY_MASKS: [WaveT; PIECE_COUNT] = {
-		let mut empty_mask: [WaveT; PIECE_COUNT] = [0; PIECE_COUNT];
-		for p in 0..PIECE_COUNT {
+	pub static ref EMPTY_MASKS: Vec<WaveT> = {
+		let max_row = geometry().max_row;
+		let mut empty_mask: Vec<WaveT> = vec![0; piece_count()];
+		for p in 0..piece_count() {
 			let mut right_edge = [false; 4];
 			let mut mask: WaveT = 0;
 
-			for x in 0..WAVE_SIZE {
+			for x in 0..geometry().wave_size {
 				let mut exceeds_max = false;
 				for row in 0..4 {
-					if PIECE_LIST[p][x][row] > MAX_ROW {
+					if PIECE_LIST[p][x][row] > max_row {
 						exceeds_max = true;
 						break;
 					}
@@ -45,22 +46,25 @@ lazy_static!{
 // This is fine; we never use the individual masks without also using the empty well mask.
 
 lazy_static!{
-	pub static ref ROW_MASKS: [[[WaveT; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT] = {
-		let mut row_masks: [[[WaveT; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT] 
-		= [[[0; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT];
+	pub static ref ROW_MASKS: Vec<Vec<Vec<[WaveT; 4]>>> = {
+		let g = geometry();
+		let mut row_masks: Vec<Vec<Vec<[WaveT; 4]>>> = vec![vec![vec![[0; 4]; 1 << g.row_chunk]; g.row_chunks]; piece_count()];
 	
-		for p in 0..PIECE_COUNT {
-			for conf in 0..=MAX_ROW {
-				for row in 0..4 {
-					let mut mask = 0;
-		
-					for w in 0..WAVE_SIZE {
-						mask <<= 1;
-						if PIECE_LIST[p][w][row] & conf == 0 {
-							mask += 1;
+		for p in 0..piece_count() {
+			for (chunk, chunk_masks) in row_masks[p].iter_mut().enumerate() {
+				for (value, value_masks) in chunk_masks.iter_mut().enumerate() {
+					let conf = (value as RowT) << (chunk * g.row_chunk);
+					for row in 0..4 {
+						let mut mask = 0;
+			
+						for w in 0..g.wave_size {
+							mask <<= 1;
+							if PIECE_LIST[p][w][row] & conf == 0 {
+								mask += 1;
+							}
 						}
+						value_masks[row] = mask;
 					}
-					row_masks[p][conf as usize][row] = mask;
 				}
 			}
 		};
@@ -69,14 +73,14 @@ lazy_static!{
 }
 
 lazy_static!{
-	pub static ref HEIGHT_MASKS: [[WaveT; 4]; PIECE_COUNT] = {
-		let mut height_masks: [[WaveT; 4]; PIECE_COUNT] = [[0; 4]; PIECE_COUNT];
-		for p in 0..PIECE_COUNT {
+	pub static ref HEIGHT_MASKS: Vec<[WaveT; 4]> = {
+		let mut height_masks: Vec<[WaveT; 4]> = vec![[0; 4]; piece_count()];
+		for p in 0..piece_count() {
 			let mut mask = 0;
 			for row in 0..4 {
 				let mut tmp_mask = 0;
 
-				for w in 0..WAVE_SIZE {
+				for w in 0..geometry().wave_size {
 					tmp_mask <<= 1;
 					if PIECE_LIST[p][w][row] != 0 {
 						tmp_mask += 1;
@@ -91,23 +95,30 @@ lazy_static!{
 	};
 }
 
+// A score mask is 1 where the piece fills every empty cell of its chunk of the row.
+// A row clears when all of its chunks are filled; score_slice() skips the floor, which is already full.
+
 lazy_static!{
-	pub static ref SCORE_MASKS: [[[WaveT; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT] = {
-		let mut score_masks: [[[WaveT; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT] 
-			= [[[0; 4]; (MAX_ROW + 1) as usize]; PIECE_COUNT];
+	pub static ref SCORE_MASKS: Vec<Vec<Vec<[WaveT; 4]>>> = {
+		let g = geometry();
+		let mut score_masks: Vec<Vec<Vec<[WaveT; 4]>>> = vec![vec![vec![[0; 4]; 1 << g.row_chunk]; g.row_chunks]; piece_count()];
 	
-		for p in 0..PIECE_COUNT {
-			for conf in 0..=MAX_ROW {
-				for row in 0..4 {
-					let mut mask = 0;
-		
-					for w in 0..WAVE_SIZE {
-						mask <<= 1;
-						if PIECE_LIST[p][w][row] + conf == MAX_ROW && conf != MAX_ROW {
-							mask += 1;
+		for p in 0..piece_count() {
+			for (chunk, chunk_masks) in score_masks[p].iter_mut().enumerate() {
+				let full = ((((1 as RowT) << g.row_chunk) - 1) << (chunk * g.row_chunk)) & g.max_row;
+				for (value, value_masks) in chunk_masks.iter_mut().enumerate() {
+					let conf = ((value as RowT) << (chunk * g.row_chunk)) & full;
+					for row in 0..4 {
+						let mut mask = 0;
+			
+						for w in 0..g.wave_size {
+							mask <<= 1;
+							if (PIECE_LIST[p][w][row] | conf) & full == full {
+								mask += 1;
+							}
 						}
+						value_masks[row] = mask;
 					}
-					score_masks[p][conf as usize][row] = mask;
 				}
 			}
 		};
@@ -115,11 +126,19 @@ lazy_static!{
 	};
 }
 
+// Index into the chunked ROW_MASKS and SCORE_MASKS tables.
+
+pub fn row_chunk_index(row: RowT, chunk: usize) -> usize {
+	let g = geometry();
+	((row as usize) >> (chunk * g.row_chunk)) & ((1 << g.row_chunk) - 1)
+}
+
 lazy_static! {
-	pub static ref SURFACE_LINE_ARRAY: Vec<Vec<u16>> = {
-		let mut surface_line_array = vec![vec![0; (MAX_ROW + 1) as usize]; (MAX_ROW + 1) as usize];
-		for i in 0..=MAX_ROW {
-			for j in 0..=MAX_ROW {
+	pub static ref SURFACE_LINE_ARRAY: Vec<Vec<RowT>> = {
+		let max_row = geometry().max_row;
+		let mut surface_line_array = vec![vec![0; (max_row + 1) as usize]; (max_row + 1) as usize];
+		for i in 0..=max_row {
+			for j in 0..=max_row {
 				// We want a new surface consisting of 0s and 1s.
 				// A bit within a new surface will be 1 if both the corresponding bit on the previous surface and the new line are 1.
 				// A bit within a new surface will also be 1 if the corresponding bit on the new line is 1 and a bit to either side is 1.
@@ -128,16 +147,16 @@ lazy_static! {
 				let previous_surface = i;
 				let current_line = !j;
 				let mut new_surface = previous_surface & current_line;
-				new_surface &= MAX_ROW;
+				new_surface &= max_row;
 
 				let mut old_surface = 0;
 				while old_surface != new_surface {
 					old_surface = new_surface;
 					new_surface = ((new_surface << 1) | (new_surface >> 1) | new_surface) & current_line;
-					new_surface &= MAX_ROW;
+					new_surface &= max_row;
 				}
 
-				surface_line_array[i as usize][j as usize] = new_surface & MAX_ROW;
+				surface_line_array[i as usize][j as usize] = new_surface & max_row;
 			}
 		}
 		surface_line_array
@@ -1,12 +1,14 @@
+use crate::compare::compare_networks;
 use crate::constants::{
-    AEON, ALL_CONV, ALPHA, CONVOLUTIONS, CONV_COUNT, EFF_HEIGHT, EPS, HIDDEN, MAX_EPOCHS,
-    MINIBATCH, NET_VERSION, REPLAY, REPLAY_NET, RHO, RHO_F, THREAD_NUMBER, VERSION, WIDTH,
+    AEON, ALPHA, EPS, HIDDEN, MAX_EPOCHS, MINIBATCH, NET_VERSION, PROMOTION_BEAM_WIDTH,
+    PROMOTION_GAMES, PROMOTION_HATETRIS, RHO, RHO_F, THREAD_NUMBER,
 };
-use crate::database::extract_data_points;
+use crate::database::{extract_data_points, load_states};
+use crate::geometry::geometry;
 use crate::searches::beam_search_network;
+use crate::seed::init_seed;
 use crate::types::{RowT, SearchConf, State, WeightT, WellT};
 
-use std::env::args;
 // use std::arch::x86_64::{__m256d, _mm256_add_pd};
 // use std::simd::f64x4;
 use std::thread;
@@ -26,57 +28,56 @@ use savefile::prelude::*;
 // If the timing persists even for warm cache, investigate this as possible bottleneck.
 // Since the time is mostly vector memory allocation, maybe have this return an iterator?
 
-pub fn decompose_well(well: &WellT) -> [usize; CONV_COUNT] {
-    let mut to_return = [0; CONV_COUNT];
+pub fn decompose_well(well: &WellT) -> Vec<usize> {
+    let g = geometry();
+    let mut to_return = Vec::with_capacity(g.conv_count);
     let mut offset = 0;
-    let mut count = 0;
-    for (m, n) in CONVOLUTIONS {
+    for (m, n) in g.convolutions {
         let mut mask = ((1 << n) - 1) as RowT;
-        let mut masked_well = well.clone();
-        for r in 0..EFF_HEIGHT {
-            masked_well[r] &= (mask as RowT);
+        let mut masked_well = *well;
+        for row in masked_well.iter_mut().take(g.eff_height) {
+            *row &= (mask as RowT);
         }
-        for col in 0..=(WIDTH - n) {
-            for r in 0..EFF_HEIGHT {
+        for col in 0..=(g.width - n) {
+            for r in 0..g.eff_height {
                 masked_well[r] = (well[r] & mask) >> col;
             }
-            for row in 0..=(EFF_HEIGHT - m) {
+            for row in 0..=(g.eff_height - m) {
                 let mut conv: usize = 0;
                 for i in 0..m {
                     conv <<= n;
                     conv += (masked_well[row + i] as usize);
                 }
-                to_return[count] = conv + offset;
-                count += 1;
+                to_return.push(conv + offset);
                 offset += 1 << (m * n);
             }
             mask <<= 1;
         }
     }
 
-    return to_return;
+    to_return
 }
 
 // Takes convolution list and weights, and returns total loss.
 // This does not keep or return internal neuron values.
 
-pub fn forward_pass(conv_list: [usize; CONV_COUNT], weight: &WeightT) -> f64 {
+pub fn forward_pass(conv_list: &[usize], weight: &WeightT) -> f64 {
     let mut output = 0.0;
     let mut hidden = [0.0; HIDDEN];
 
-    for c in conv_list {
-        for h in 0..HIDDEN {
-            hidden[h] += weight.conv[c][h];
+    for &c in conv_list {
+        for (h, w) in hidden.iter_mut().zip(weight.conv[c].iter()) {
+            *h += w;
         }
     }
 
-    for h in 0..HIDDEN {
-        hidden[h] = hidden[h].tanh();
-        output += hidden[h] * weight.hidden[h];
+    for (h, w) in hidden.iter_mut().zip(weight.hidden.iter()) {
+        *h = h.tanh();
+        output += *h * w;
     }
     output = output.tanh();
 
-    return output;
+    output
 }
 
 // Takes convolution list and weights, and returns total loss.
@@ -128,38 +129,35 @@ pub fn forward_pass(conv_list: [usize; CONV_COUNT], weight: &WeightT) -> f64 {
 // Takes convolution list and weights, and returns a tuple (inputs, hidden, loss).
 // Used for training and backpropagation.
 
-pub fn forward_pass_memory(
-    conv_list: [usize; CONV_COUNT],
-    weight: &WeightT,
-) -> ([f64; HIDDEN], f64) {
+pub fn forward_pass_memory(conv_list: &[usize], weight: &WeightT) -> ([f64; HIDDEN], f64) {
     //let mut inputs = [0.0; ALL_CONV];
     let mut pre_hidden = [0.0; HIDDEN];
     let mut hidden = [0.0; HIDDEN];
     let mut pre_output = 0.0;
-    let output: f64;
+    
 
     // for c in conv_list {
     // 	inputs[c] += 1.0;
     // }
 
-    for c in conv_list {
-        for h in 0..HIDDEN {
-            pre_hidden[h] += weight.conv[c][h];
+    for &c in conv_list {
+        for (h, w) in pre_hidden.iter_mut().zip(weight.conv[c].iter()) {
+            *h += w;
         }
     }
     for h in 0..HIDDEN {
         hidden[h] = pre_hidden[h].tanh();
         pre_output += hidden[h] * weight.hidden[h];
     }
-    output = pre_output.tanh();
+    let output: f64 = pre_output.tanh();
 
-    return (hidden, output);
+    (hidden, output)
 }
 
 pub fn tanh_discrete(x: i16, s: u8) -> i16 {
     let n = x >> s;
     let num = n.abs().min(127) - 127;
-    return (x.signum() * (126 - ((num * num) >> 7))) as i16;
+    (x.signum() * (126 - ((num * num) >> 7)))
 }
 
 // Takes training data and backpropagates until either:
@@ -217,7 +215,7 @@ pub fn train_network(
 
         for t in epoch * MINIBATCH..(epoch + 1) * MINIBATCH {
             let conv_list = decompose_well(&training[t].0);
-            let (hidden, output) = forward_pass_memory(conv_list, &new_weights);
+            let (hidden, output) = forward_pass_memory(&conv_list, &new_weights);
             let goal = training[t].1;
             goals[t - epoch * MINIBATCH] = goal;
 
@@ -271,16 +269,16 @@ pub fn train_network(
             //	g_inputs = Transpose(WI) * g_pre_hidden
             //	WI_inc = Σ_(h in pre_hidden)_(h * WI)		[Elementwise]
 
-            for c in conv_list {
+            for &c in &conv_list {
                 // All other nodes c are incremented by zero, by definition.
-                for h in 0..HIDDEN {
-                    inc.conv[c][h] += g_pre_hidden[h];
+                for (i, g) in inc.conv[c].iter_mut().zip(g_pre_hidden.iter()) {
+                    *i += g;
                 }
             }
         }
 
         // Adam Optimizer
-        for c in 0..ALL_CONV {
+        for c in 0..new_weights.conv.len() {
             // First layer weights
             // WI_inc = Σ_(h in pre_hidden)_(h * WI)		[Elementwise]
             for h in 0..HIDDEN {
@@ -312,15 +310,15 @@ pub fn train_network(
 
         for t in epoch * MINIBATCH..(epoch + 1) * MINIBATCH {
             let conv_list = decompose_well(&training[t].0);
-            let output = forward_pass(conv_list, &new_weights);
+            let output = forward_pass(&conv_list, &new_weights);
             let goal = goals[t - epoch * MINIBATCH];
             let loss = (goal - output) * (goal - output);
 
             ave_loss += loss;
         }
 
-        ave_pre_loss = ave_pre_loss / (MINIBATCH as f64);
-        ave_loss = ave_loss / (MINIBATCH as f64);
+        ave_pre_loss /= (MINIBATCH as f64);
+        ave_loss /= (MINIBATCH as f64);
         epoch += 1;
 
         println!("Epoch {}: Loss: {} -> {}", epoch, ave_pre_loss, ave_loss);
@@ -338,7 +336,7 @@ pub fn train_network(
         }
     }
 
-    return best_weights;
+    best_weights
 }
 
 pub fn generate_training_data(
@@ -354,10 +352,9 @@ pub fn generate_training_data(
         let training_conf = SearchConf::training(generation);
 
         for well in states {
-            let best_result = beam_search_network(&well, &weight, &training_conf) as f64;
-            let goal_heuristic = best_result;
+            let goal_heuristic = beam_search_network(&well, &weight, &training_conf).heuristic;
 
-            training_data.push((well.well.clone(), goal_heuristic));
+            training_data.push((*well.well(), goal_heuristic));
         }
 
         let epoch_file_name = conf.epoch_path(epoch);
@@ -370,10 +367,25 @@ pub fn generate_training_data(
         );
     });
 
-    return thread;
+    thread
+}
+
+// Loads a saved network, checking that it was trained for this well geometry.
+
+pub fn load_network(path: &str) -> WeightT {
+    let weight: WeightT = load_file(path, NET_VERSION)
+        .unwrap_or_else(|_| panic!("Could not load the network at {}.", path));
+    if weight.conv.len() != geometry().all_conv {
+        panic!(
+            "The network has {} convolutional nodes, but this well geometry needs {}.",
+            weight.conv.len(),
+            geometry().all_conv
+        );
+    }
+    weight
 }
 
-pub fn training_cycle() -> () {
+pub fn training_cycle() {
     let mut master_conf = SearchConf::master(0);
     let mut generation = 0;
 
@@ -385,6 +397,8 @@ pub fn training_cycle() -> () {
     } else {
         println!("Continuing training cycle {}.", AEON);
     }
+    let seed = init_seed(&master_conf.seed_path());
+    println!("Run seed: {}", seed);
 
     let mut weight = WeightT::new();
     let mut gen_folder_name = master_conf.generation_path();
@@ -411,29 +425,19 @@ pub fn training_cycle() -> () {
 
         let neural_network_path = master_conf.neural_network_path();
         if Path::new(&neural_network_path).exists() {
-            weight = load_file(&neural_network_path, NET_VERSION).unwrap();
+            weight = load_network(&neural_network_path);
             println!("Loading neural network from generation {}", generation);
         } else {
             panic!("No neural network found at generation {}!", generation);
         }
     }
 
-    if REPLAY {
-        if let Some(dir) = REPLAY_NET {
-            let mut v = args();
-            v.next();
-            let gen = v.next().unwrap();
-            println!("{}", dir.replace("{}", &gen));
-            weight = load_file(&dir.replace("{}", &gen), NET_VERSION).unwrap();
-        }
-    }
-
     // Main training loop.
     // We now know what aeon and generation we are in.
 
     loop {
         let training_path = master_conf.training_path();
-        if Path::new(&training_path).exists() && !REPLAY {
+        if Path::new(&training_path).exists() {
             let mut epoch = 0;
             let mut epoch_file_name = master_conf.epoch_path(epoch);
             while Path::new(&epoch_file_name).exists() {
@@ -451,7 +455,7 @@ pub fn training_cycle() -> () {
             let starting_epoch = (epoch + 1) as usize;
             let mut thread_list: Vec<JoinHandle<()>> = vec![];
 
-            let training_data: Vec<State> = load_file(&master_conf.data_path(), VERSION).unwrap();
+            let training_data = load_states(&master_conf.data_path());
             println!("{} training wells loaded.", training_data.len());
 
             while epoch < MAX_EPOCHS {
@@ -493,17 +497,57 @@ pub fn training_cycle() -> () {
             }
 
             let new_weight = train_network(&all_training, &weight, &master_conf);
-            weight = new_weight.clone();
 
             generation += 1;
             master_conf.generation = generation;
 
+            // The new network must not be significantly worse than the one it was trained from.
+            // The comparison runs before the generation folder exists, so an interrupted one is run again.
+            let comparison = if PROMOTION_GAMES != 0 {
+                let mut conf = SearchConf::master(generation);
+                conf.beam_width = PROMOTION_BEAM_WIDTH;
+                conf.save = false;
+                conf.print = false;
+                Some(compare_networks(
+                    &new_weight,
+                    &weight,
+                    &conf,
+                    PROMOTION_HATETRIS,
+                    PROMOTION_GAMES,
+                ))
+            } else {
+                None
+            };
+            let promoted = comparison.as_ref().is_none_or(|c| c.promotes());
+            let rejected = if promoted {
+                weight = new_weight;
+                None
+            } else {
+                Some(new_weight)
+            };
+
             gen_folder_name = master_conf.generation_path();
             fs::create_dir_all(gen_folder_name).expect("Could not create generation folder.");
 
             let neural_network_path = master_conf.neural_network_path();
             save_file(&neural_network_path, 0, &weight).unwrap();
 
+            if let Some(comparison) = comparison {
+                print!("{}", comparison.report());
+                fs::write(
+                    master_conf.promotion_path(),
+                    format!("{}Promoted: {}\n", comparison.report(), promoted),
+                )
+                .expect("Could not write promotion result.");
+            }
+            if let Some(new_weight) = rejected {
+                save_file(&master_conf.rejected_network_path(), 0, &new_weight).unwrap();
+                println!(
+                    "The new network is worse; generation {} keeps the previous one.",
+                    generation
+                );
+            }
+
             println!("Neural network created for generation {}", generation);
         } else {
             // If the training data folder does not exist, we need to run the beam search.
@@ -520,7 +564,7 @@ pub fn training_cycle() -> () {
                 .expect("Could not create training data folder.");
 
             // Save the extracted data points in the newly created training data folder.
-            save_file(&conf.data_path(), VERSION, &training).unwrap();
+            save_file(&conf.data_path(), geometry().version(), &training).unwrap();
         }
     }
 }
@@ -0,0 +1,328 @@
+// Perft: counting positions and placements to a fixed depth, to validate the move generator.
+
+// Every well at each depth is expanded with the piece the selector chooses, and two numbers are counted:
+//		placements: every legal placement, including ones that lead to the same well.
+//		positions: the distinct wells that result.
+// The counts are computed twice, once with the waveform move generator and once with a naive reference
+// that moves the piece cell by cell from its spawn, as HATETRIS does. Any change to waveform_step(),
+// resting_waveforms() or the masks should leave the counts unchanged:
+//		cargo run --release -- perft 4
+//		cargo run --release -- perft 3 bag:7 0011111111 0111111111
+
+use crate::emulator::{get_legal, get_well_height, select_piece, spawn_position, wave_placements};
+use crate::constants::MAX_EFF_HEIGHT;
+use crate::geometry::geometry;
+use crate::pieces::{piece_count, pieces};
+use crate::types::{scoring, PieceSelector, Placement, RowT, State, WellT};
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use fnv::FnvHashSet;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PerftCounts {
+    pub depth: usize,
+    pub positions: usize,
+    pub placements: usize,
+}
+
+// Whether piece p fits at waveform column x, rotation rot and height h, checking every cell.
+// Rows above the line are empty; rows below the well are the floor.
+
+fn naive_fits(p: usize, x: usize, rot: usize, h: usize, well: &WellT) -> bool {
+    let g = geometry();
+    for r in 0..4 {
+        for c in 0..4 {
+            if pieces()[p].rotations[rot][r] & (1 << (3 - c)) == 0 {
+                continue;
+            }
+            let row = (h + r) as isize - 4;
+            let col = (x + c) as isize - 2;
+            if col < 0 || col >= g.width as isize || row >= g.eff_height as isize {
+                return false;
+            }
+            if row >= 0 && well[row as usize] & (1 << (g.width - 1 - col as usize)) != 0 {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn naive_lock(p: usize, x: usize, rot: usize, h: usize, state: &State) -> Option<(State, usize)> {
+    let g = geometry();
+    let mut well = *state.well();
+    for r in 0..4 {
+        for c in 0..4 {
+            if pieces()[p].rotations[rot][r] & (1 << (3 - c)) == 0 {
+                continue;
+            }
+            let row = (h + r) as isize - 4;
+            if row < 0 {
+                // Locking above the line ends the game.
+                return None;
+            }
+            well[row as usize] |= 1 << (g.width + 1 - x - c);
+        }
+    }
+
+    let mut new_well = [0; MAX_EFF_HEIGHT];
+    let mut lines = 0;
+    for row in (0..g.eff_height).rev() {
+        if well[row] == g.max_row {
+            lines += 1;
+        } else {
+            new_well[row + lines] = well[row];
+        }
+    }
+    let new_state = State::from_well(new_well, scoring().add(state.score, lines));
+    Some((new_state, lines))
+}
+
+// Every placement of piece p reachable from its spawn with Left, Right, Up (rotate) and Down,
+// found by a breadth first search over single cell moves.
+
+pub fn naive_placements(p: usize, state: &State) -> Vec<Placement> {
+    let g = geometry();
+    let fits = |(x, rot, h): (usize, usize, usize)| naive_fits(p, x, rot, h, state.well());
+
+    let (i, h) = spawn_position(p);
+    let start = (i / 4, i % 4, h);
+    if !fits(start) {
+        return vec![];
+    }
+
+    let mut seen = FnvHashSet::default();
+    let mut queue = VecDeque::new();
+    seen.insert(start);
+    queue.push_back(start);
+
+    let mut placements = vec![];
+    while let Some((x, rot, h)) = queue.pop_front() {
+        if !fits((x, rot, h + 1)) {
+            if let Some((new_state, lines)) = naive_lock(p, x, rot, h, state) {
+                placements.push(Placement {
+                    piece: p,
+                    rotation: rot,
+                    x,
+                    height: h,
+                    lines,
+                    state: new_state,
+                });
+            }
+        }
+
+        let mut moves = vec![(x, (rot + 1) % 4, h), (x, rot, h + 1)];
+        if x > 0 {
+            moves.push((x - 1, rot, h));
+        }
+        if x < g.width {
+            moves.push((x + 1, rot, h));
+        }
+        for next in moves {
+            if fits(next) && seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+
+    placements
+}
+
+// HATETRIS's choice, computed from the naive placements: the piece whose best placement
+// leaves the highest stack, ties going to the earliest piece.
+
+pub fn naive_worst_piece(state: &State) -> usize {
+    let mut worst = 0;
+    let mut lowest = isize::MAX;
+    for p in 0..piece_count() {
+        let rating = naive_placements(p, state)
+            .iter()
+            .map(|pl| get_well_height(pl.state.well()) as isize)
+            .max()
+            .unwrap_or(-(geometry().well_line as isize));
+        if rating < lowest {
+            worst = p;
+            lowest = rating;
+        }
+    }
+    worst
+}
+
+// The piece the selector chooses, using only the naive move generator.
+
+pub fn naive_select_piece(selector: &PieceSelector, state: &State, depth: usize) -> usize {
+    match selector {
+        PieceSelector::Hatetris => naive_worst_piece(state),
+        _ => select_piece(selector, state, &[], depth, None),
+    }
+}
+
+// Counts for depths 1 to max_depth, where depth d is the result of d moves from the starting state.
+// The selector sees the move number, so depth d is expanded as move d of a game.
+
+pub fn perft(
+    starting_state: &State,
+    selector: &PieceSelector,
+    max_depth: usize,
+    naive: bool,
+) -> Vec<PerftCounts> {
+    let mut counts = vec![];
+    let mut level: Vec<State> = vec![starting_state.clone()];
+
+    for depth in 0..max_depth {
+        let mut next: FnvHashSet<State> = FnvHashSet::default();
+        let mut placements = 0;
+        for state in &level {
+            let children = if naive {
+                let p = naive_select_piece(selector, state, depth);
+                naive_placements(p, state)
+            } else {
+                let (p, all_waves) = get_legal(state, selector, depth, None);
+                wave_placements(&all_waves[p], p, state)
+            };
+            placements += children.len();
+            next.extend(children.into_iter().map(|pl| pl.state));
+        }
+
+        counts.push(PerftCounts {
+            depth: depth + 1,
+            positions: next.len(),
+            placements,
+        });
+        level = next.into_iter().collect();
+    }
+
+    counts
+}
+
+// Reads a well from its bottom rows, listed top to bottom as strings of 0 and 1, leftmost column first.
+
+pub fn parse_well(rows: &[String]) -> Result<State, String> {
+    let g = geometry();
+    if rows.len() > g.eff_height {
+        return Err(format!("The well only has {} rows below the line.", g.eff_height));
+    }
+
+    let mut state = State::new();
+    for (r, row) in rows.iter().enumerate() {
+        if row.len() != g.width || row.chars().any(|c| c != '0' && c != '1') {
+            return Err(format!("Row {} must be {} characters of 0 and 1.", row, g.width));
+        }
+        let value = RowT::from_str_radix(row, 2).unwrap();
+        if value == g.max_row {
+            return Err(format!("Row {} is full.", row));
+        }
+        state.set_row(g.eff_height - rows.len() + r, value);
+    }
+    Ok(state)
+}
+
+// perft DEPTH [SELECTOR] [ROW ...]
+// Prints the counts from both move generators and exits with status 1 if they differ.
+// Arguments that do not parse, such as a malformed row, are reported with the usage, also with status 1.
+
+pub fn perft_command(args: &[String]) {
+    let usage = "Usage: perft DEPTH [hatetris|random|bag:SEED|seq:P,P,...] [ROW ...]";
+    let fail = |e: String| -> ! {
+        println!("{}\n{}", e, usage);
+        std::process::exit(1)
+    };
+    let depth: usize = args
+        .first()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or_else(|| fail(String::from("No depth given.")));
+
+    let mut rows = &args[1.min(args.len())..];
+    let mut selector = PieceSelector::Hatetris;
+    if let Some(first) = rows.first() {
+        if !first.starts_with(['0', '1']) {
+            selector = PieceSelector::parse(first).unwrap_or_else(|e| fail(e));
+            rows = &rows[1..];
+        }
+    }
+    let state = parse_well(rows).unwrap_or_else(|e| fail(e));
+
+    println!("Selector: {:?}", selector);
+    println!("Depth  Positions  Placements  Naive positions  Naive placements");
+
+    let start = Instant::now();
+    let counts = perft(&state, &selector, depth, false);
+    let wave_seconds = start.elapsed().as_secs_f64();
+    let naive_counts = perft(&state, &selector, depth, true);
+    let naive_seconds = start.elapsed().as_secs_f64() - wave_seconds;
+
+    let mut mismatch = false;
+    for (c, n) in counts.iter().zip(naive_counts.iter()) {
+        let flag = if c == n { "" } else { "  MISMATCH" };
+        mismatch |= c != n;
+        println!(
+            "{:5}  {:9}  {:10}  {:15}  {:16}{}",
+            c.depth, c.positions, c.placements, n.positions, n.placements, flag
+        );
+    }
+    println!("Waveform generator: {:.3} seconds, naive generator: {:.3} seconds.", wave_seconds, naive_seconds);
+
+    if mismatch {
+        println!("The move generators disagree.");
+        std::process::exit(1);
+    }
+    println!("The move generators agree.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::piece_placements;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn sorted(mut placements: Vec<Placement>) -> Vec<Placement> {
+        placements.sort_by_key(|pl| (pl.x, pl.rotation, pl.height));
+        placements
+    }
+
+    #[test]
+    fn naive_placements_match_waveforms_on_random_wells() {
+        let g = geometry();
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..500 {
+            let mut state = State::new();
+            for r in rng.gen_range(0..g.eff_height)..g.eff_height {
+                state.set_row(r, rng.gen_range(0..g.max_row));
+            }
+            for p in 0..piece_count() {
+                assert_eq!(sorted(piece_placements(p, &state)), sorted(naive_placements(p, &state)));
+            }
+        }
+    }
+
+    #[test]
+    fn first_move_counts() {
+        // HATETRIS opens with an S: 2 rotations of each of the w - 2 flat and w - 1 upright placements.
+        let w = geometry().width;
+        let counts = perft(&State::new(), &PieceSelector::Hatetris, 1, false);
+        assert_eq!(counts[0].placements, 2 * (w - 2) + 2 * (w - 1));
+        assert_eq!(counts[0].positions, (w - 2) + (w - 1));
+    }
+
+    #[test]
+    fn generators_agree() {
+        for selector in [PieceSelector::Hatetris, PieceSelector::Bag(3)] {
+            let counts = perft(&State::new(), &selector, 2, false);
+            assert_eq!(counts, perft(&State::new(), &selector, 2, true));
+        }
+    }
+
+    #[test]
+    fn wells_parse_from_rows() {
+        let w = geometry().width;
+        let row = format!("{}{}", "1".repeat(w - 1), "0");
+        let state = parse_well(&[row]).unwrap();
+        assert_eq!(state.well()[geometry().eff_height - 1], geometry().max_row - 1);
+        assert!(parse_well(&["1".repeat(w)]).is_err());
+        assert!(parse_well(&["1".repeat(w - 1)]).is_err());
+    }
+}
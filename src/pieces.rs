@@ -1,14 +1,41 @@
-// Piece list and base waveform generation
+// Piece sets and base waveform generation
+
+// The piece set is loaded once, the first time pieces() is called, from the file named by the
+// PIECE_SET environment variable, falling back to the HATETRIS tetrominoes in BASE_PIECES:
+//		PIECE_SET=pieces/srs.txt cargo run --release
+// A piece set file lists each piece as a header line followed by 4 lines of 4 columns,
+// holding the 4 rotation states side by side in clockwise order ("U" goes from one to the next):
+//		piece T
+//		.... .... .... .#..
+//		.### ..#. .#.. .##.
+//		..#. .##. ###. .#..
+//		.... ..#. .... ....
+// '#' is a filled cell and '.' an empty one. Blank lines and lines starting with // are ignored.
+// The header can also give the spawn column, relative to HATETRIS's, and the spawn rotation:
+//		piece T 1 2
+// Every piece must fit its 4x4 box and have exactly 4 rotation states; symmetric pieces repeat them.
+// Rotation never kicks, as in HATETRIS.
 
 use lazy_static::lazy_static;
 
-use crate::constants::{WAVE_SIZE, WIDTH};
+use crate::geometry::{geometry};
 use crate::types::{RowT};
 
-pub const PIECE_COUNT: usize = 7;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Piece {
+	pub name: String,
+	pub rotations: [[RowT; 4]; 4],
+	pub spawn_offset: isize,
+	pub spawn_rotation: usize,
+}
 
 // All pieces must have their bounding boxes aligned with the right well edge.
-pub const BASE_PIECES: [[[RowT; 4]; 4]; PIECE_COUNT] = [
+pub const BASE_PIECES: [[[RowT; 4]; 4]; 7] = [
 	[[0,3,6,0], [0,4,6,2], [0,6,12,0], [4,6,2,0]],  // S
 	[[0,6,3,0], [0,2,6,4], [0,12,6,0], [2,6,4,0]],  // Z
  	[[0,6,6,0], [0,6,6,0], [0,6,6,0], [0,6,6,0]],   // O
@@ -18,17 +45,143 @@ pub const BASE_PIECES: [[[RowT; 4]; 4]; PIECE_COUNT] = [
 	[[0,7,2,0], [0,2,6,2], [0,4,14,0], [4,6,4,0]],  // T
 ];
 
+pub const BASE_PIECE_NAMES: [&str; 7] = ["S", "Z", "O", "I", "L", "J", "T"];
+
+pub fn hatetris_pieces() -> Vec<Piece> {
+	(0..BASE_PIECES.len()).map(|p| Piece {
+		name: String::from(BASE_PIECE_NAMES[p]),
+		rotations: BASE_PIECES[p],
+		spawn_offset: 0,
+		spawn_rotation: 0,
+	}).collect()
+}
+
+pub fn parse_pieces(text: &str) -> Result<Vec<Piece>, String> {
+	let lines: Vec<(usize, &str)> = text.lines()
+		.map(|l| l.trim())
+		.enumerate()
+		.filter(|(_, l)| !l.is_empty() && !l.starts_with("//"))
+		.collect();
+
+	let mut pieces = vec![];
+	let mut i = 0;
+	while i < lines.len() {
+		let (n, header) = lines[i];
+		let fields: Vec<&str> = header.split_whitespace().collect();
+		if fields[0] != "piece" || (fields.len() != 2 && fields.len() != 4) {
+			return Err(format!("Line {}: expected \"piece NAME [COLUMN ROTATION]\", found \"{}\".", n + 1, header));
+		}
+
+		let mut piece = Piece {
+			name: String::from(fields[1]),
+			rotations: [[0; 4]; 4],
+			spawn_offset: 0,
+			spawn_rotation: 0,
+		};
+		if fields.len() == 4 {
+			piece.spawn_offset = fields[2].parse().map_err(|_| format!("Line {}: bad spawn column {}.", n + 1, fields[2]))?;
+			piece.spawn_rotation = fields[3].parse().map_err(|_| format!("Line {}: bad spawn rotation {}.", n + 1, fields[3]))?;
+			if piece.spawn_rotation >= 4 {
+				return Err(format!("Line {}: the spawn rotation must be between 0 and 3.", n + 1));
+			}
+		}
+
+		if i + 5 > lines.len() {
+			return Err(format!("Piece {} needs 4 rows.", piece.name));
+		}
+		for row in 0..4 {
+			let (n, line) = lines[i + 1 + row];
+			let states: Vec<&str> = line.split_whitespace().collect();
+			if states.len() != 4 || states.iter().any(|s| s.chars().count() != 4) {
+				return Err(format!("Line {}: expected 4 rotation states of 4 columns, found \"{}\".", n + 1, line));
+			}
+			for (rot, s) in states.iter().enumerate() {
+				for c in s.chars() {
+					piece.rotations[rot][row] <<= 1;
+					match c {
+						'#' => piece.rotations[rot][row] += 1,
+						'.' => {},
+						_ => return Err(format!("Line {}: unknown cell '{}'.", n + 1, c)),
+					}
+				}
+			}
+		}
+		if piece.rotations.iter().any(|r| r.iter().all(|&row| row == 0)) {
+			return Err(format!("Piece {} has an empty rotation state.", piece.name));
+		}
+
+		pieces.push(piece);
+		i += 5;
+	}
+
+	if pieces.is_empty() {
+		return Err(String::from("The piece set is empty."));
+	}
+	Ok(pieces)
+}
+
+// The inverse of parse_pieces().
+
+pub fn format_pieces(pieces: &[Piece]) -> String {
+	let mut text = String::new();
+	for piece in pieces {
+		if piece.spawn_offset != 0 || piece.spawn_rotation != 0 {
+			text += &format!("piece {} {} {}\n", piece.name, piece.spawn_offset, piece.spawn_rotation);
+		} else {
+			text += &format!("piece {}\n", piece.name);
+		}
+		for row in 0..4 {
+			let states: Vec<String> = piece.rotations.iter()
+				.map(|r| (0..4).rev().map(|c| if (r[row] >> c) % 2 == 1 {'#'} else {'.'}).collect())
+				.collect();
+			text += &states.join(" ");
+			text += "\n";
+		}
+		text += "\n";
+	}
+	text
+}
+
+pub fn load_pieces(path: &str) -> Result<Vec<Piece>, String> {
+	let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+	parse_pieces(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+static PIECES: OnceLock<Vec<Piece>> = OnceLock::new();
+
+pub fn pieces() -> &'static Vec<Piece> {
+	PIECES.get_or_init(|| {
+		match env::var("PIECE_SET") {
+			Ok(path) => load_pieces(&path).unwrap_or_else(|e| panic!("{}", e)),
+			Err(_) => hatetris_pieces(),
+		}
+	})
+}
+
+pub fn piece_count() -> usize {
+	pieces().len()
+}
+
+// The name of the loaded piece set file, or None for the built-in HATETRIS set.
+
+pub fn piece_set_name() -> Option<String> {
+	env::var("PIECE_SET").ok().map(|path| {
+		Path::new(&path).file_stem().map_or(path.clone(), |s| s.to_string_lossy().into_owned())
+	})
+}
+
 lazy_static! {
-	pub static ref PIECE_LIST: [[[RowT; 4]; WAVE_SIZE]; PIECE_COUNT] = {
-		let mut piece_list = [[[0; 4]; WAVE_SIZE]; PIECE_COUNT];
-		for p in 0..PIECE_COUNT {
-			for x in 0..WIDTH+1 {
+	pub static ref PIECE_LIST: Vec<Vec<[RowT; 4]>> = {
+		let width = geometry().width;
+		let mut piece_list = vec![vec![[0; 4]; geometry().wave_size]; piece_count()];
+		for (positions, piece) in piece_list.iter_mut().zip(pieces().iter()) {
+			for x in 0..width+1 {
 				for rot in 0..4 {
-					for row in 0..4 {
-						if x < WIDTH - 2 {
-							piece_list[p][rot + 4*x][row] = BASE_PIECES[p][rot][row] << (WIDTH - 2 - x);
+					for (row, &base) in piece.rotations[rot].iter().enumerate() {
+						if x < width - 2 {
+							positions[rot + 4*x][row] = base << (width - 2 - x);
 						} else {
-							piece_list[p][rot + 4*x][row] = BASE_PIECES[p][rot][row] >> (x - (WIDTH - 2));
+							positions[rot + 4*x][row] = base >> (x - (width - 2));
 						}
 					}
 				}
@@ -36,4 +189,44 @@ lazy_static! {
 		};
 		piece_list
 	};
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hatetris_file_matches_built_in_set() {
+		let loaded = parse_pieces(include_str!("../pieces/hatetris.txt")).unwrap();
+		assert_eq!(loaded, hatetris_pieces());
+	}
+
+	#[test]
+	fn srs_file_has_four_cells_per_state() {
+		let loaded = parse_pieces(include_str!("../pieces/srs.txt")).unwrap();
+		assert_eq!(loaded.len(), 7);
+		for piece in loaded {
+			for rot in piece.rotations {
+				assert_eq!(rot.iter().map(|row| row.count_ones()).sum::<u32>(), 4, "{}", piece.name);
+			}
+		}
+	}
+
+	#[test]
+	fn format_round_trips() {
+		let mut set = hatetris_pieces();
+		set[3].spawn_offset = -1;
+		set[3].spawn_rotation = 1;
+		assert_eq!(parse_pieces(&format_pieces(&set)).unwrap(), set);
+	}
+
+	#[test]
+	fn malformed_sets_are_rejected() {
+		assert!(parse_pieces("").is_err());
+		assert!(parse_pieces("piece X\n.... .... .... ....\n").is_err());
+		assert!(parse_pieces("piece X 0 4\n#... #... #... #...\n.... .... .... ....\n.... .... .... ....\n.... .... .... ....\n").is_err());
+		assert!(parse_pieces("piece X\n#... #... #... ....\n.... .... .... ....\n.... .... .... ....\n.... .... .... ....\n").is_err());
+		assert!(parse_pieces("piece X\n#... #... #... #x..\n.... .... .... ....\n.... .... .... ....\n.... .... .... ....\n").is_err());
+		assert!(parse_pieces("piece X\n#... #... #... #...\n.... .... .... ....\n.... .... .... ....\n.... .... .... ....\n").is_ok());
+	}
+}
@@ -0,0 +1,404 @@
+// Playing the networks of saved generations.
+
+// The replay command plays one game with a generation's network, using the master search settings
+// without saving anything, and writes what it played next to the generation's replays:
+//		cargo run --release -- replay 12
+//		cargo run --release -- replay 12 bag:7 width:10 out:replay/gen12
+// The optional arguments choose the piece selector (hatetris, random, bag:SEED or seq:P,P,...; the
+// master selector by default), the beam width and the path the files are written to, without extension.
+// With from:REPLAY:MOVE, the game starts from the well before that move of a HATETRIS replay code or file
+// instead of the empty well, see replay::replay_well().
+// Three files are written:
+//		.json: the score, the keystroke replay and, for every move, the well, its heuristic and the placement.
+//		.csv: the same moves, one per row, with the well's rows separated by slashes.
+//		.txt: the score on the first line, the keystroke replay, then the ASCII view of every well.
+// The score command evaluates a range of generations of the aeon, playing the same games with each
+// network, one game at a time, and writes the mean, standard deviation, minimum and maximum
+// score of each generation to a table in the aeon folder:
+//		cargo run --release -- score
+//		cargo run --release -- score 5 12 games:20 width:100 out:scores.csv
+// The generations run from FIRST (0 by default) to LAST, or to the last one with a network.
+// Game k draws its pieces from a 7-bag seeded by the run seed and k, so every generation sees the same
+// K games (5 by default); with the argument hatetris, each network instead plays one game against the
+// HATETRIS selector, which has no randomness.
+
+use crate::constants::MULTIPLIER;
+use crate::geometry::geometry;
+use crate::neural::load_network;
+use crate::replay::{encode_base65536, encode_hex, line_keys, replay_well};
+use crate::searches::{beam_search_network, SearchResult};
+use crate::seed::{init_seed, seed_for, GAME_STREAM};
+use crate::types::{PieceSelector, ScoreT, SearchConf, State, StateH, StatePP, WeightT};
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A replay's settings from the command line, after the generation, and the well it starts from.
+
+fn replay_conf(generation: usize, args: &[String]) -> Result<(SearchConf, Option<String>, State), String> {
+    let mut conf = SearchConf::master(generation);
+    conf.save = false;
+    let mut out = None;
+    let mut start = State::new();
+    for arg in args {
+        if let Some(width) = arg.strip_prefix("width:") {
+            conf.beam_width = width.parse().map_err(|_| format!("Bad beam width {}.", width))?;
+        } else if let Some(path) = arg.strip_prefix("out:") {
+            out = Some(String::from(path));
+        } else if let Some(from) = arg.strip_prefix("from:") {
+            let (replay, m) = from.rsplit_once(':').ok_or_else(|| format!("No move in {}.", arg))?;
+            let m = m.parse().map_err(|_| format!("Bad move {}.", m))?;
+            start = replay_well(replay, m)?;
+        } else {
+            conf.piece_selector = PieceSelector::parse(arg)?;
+        }
+    }
+    Ok((conf, out, start))
+}
+
+pub fn replay_command(args: &[String]) {
+    let usage = "Usage: replay GENERATION [hatetris|random|bag:SEED|seq:P,P,...] [width:N] [out:PATH] [from:REPLAY:MOVE]";
+    let fail = |e: String| -> ! {
+        println!("{}\n{}", e, usage);
+        std::process::exit(1)
+    };
+    let generation: usize = args
+        .first()
+        .and_then(|g| g.parse().ok())
+        .unwrap_or_else(|| fail(String::from("No generation given.")));
+    let (conf, out, start) = replay_conf(generation, &args[1..]).unwrap_or_else(|e| fail(e));
+
+    let weight = load_network(&conf.neural_network_path());
+    let seed = init_seed(&conf.seed_path());
+    println!("Run seed: {}", seed);
+    println!("Generation {}, beam width {}, {}", generation, conf.beam_width, conf.piece_selector.name());
+
+    let result = beam_search_network(&start, &weight, &conf);
+    let stem = match out {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&conf.replay_path())
+            .join(format!("replay width {} {}", conf.beam_width, conf.piece_selector.name())),
+    };
+    for path in write_replay(&result, &conf, &stem) {
+        println!("Replay written to {}", path.display());
+    }
+    println!("Score: {}", result.line.last().unwrap().score);
+}
+
+// The pieces of game k of an evaluation: a 7-bag seeded by the run seed and k, or HATETRIS's selector.
+
+pub fn game_selector(hatetris: bool, game: usize) -> PieceSelector {
+    if hatetris {
+        return PieceSelector::Hatetris;
+    }
+    PieceSelector::Bag(seed_for(&[GAME_STREAM, game as u64]))
+}
+
+// Plays games with the network, game k with game_selector(hatetris, k) and the rest of conf, returning
+// the score of each game's best line. The games run one after another, each search with its own pool.
+
+pub fn play_games(weight: &WeightT, conf: &SearchConf, hatetris: bool, games: usize) -> Vec<ScoreT> {
+    (0..games)
+        .map(|k| {
+            let mut game_conf = conf.clone();
+            game_conf.piece_selector = game_selector(hatetris, k);
+            let result = beam_search_network(&State::new(), weight, &game_conf);
+            result.line.last().unwrap().score
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreSummary {
+    pub games: usize,
+    pub mean: f64,
+    pub stdev: f64, // The sample standard deviation, 0 for one game.
+    pub min: ScoreT,
+    pub max: ScoreT,
+}
+
+pub fn summarize(scores: &[ScoreT]) -> ScoreSummary {
+    let n = scores.len() as f64;
+    let mean = scores.iter().map(|&s| s as f64).sum::<f64>() / n;
+    let squares = scores.iter().map(|&s| (s as f64 - mean).powi(2)).sum::<f64>();
+    ScoreSummary {
+        games: scores.len(),
+        mean,
+        stdev: if scores.len() > 1 { (squares / (n - 1.0)).sqrt() } else { 0.0 },
+        min: *scores.iter().min().unwrap(),
+        max: *scores.iter().max().unwrap(),
+    }
+}
+
+pub fn score_command(args: &[String]) {
+    let usage = "Usage: score [FIRST [LAST]] [hatetris] [games:N] [width:N] [out:PATH]";
+    let fail = |e: String| -> ! {
+        println!("{}\n{}", e, usage);
+        std::process::exit(1)
+    };
+    let mut generations = vec![];
+    let mut hatetris = false;
+    let mut games = 5;
+    let mut conf = SearchConf::master(0);
+    conf.save = false;
+    conf.print = false;
+    let mut out = None;
+    for arg in args {
+        if let Some(n) = arg.strip_prefix("games:") {
+            games = n.parse().unwrap_or_else(|_| fail(format!("Bad game count {}.", n)));
+        } else if let Some(width) = arg.strip_prefix("width:") {
+            conf.beam_width = width.parse().unwrap_or_else(|_| fail(format!("Bad beam width {}.", width)));
+        } else if let Some(path) = arg.strip_prefix("out:") {
+            out = Some(String::from(path));
+        } else if arg == "hatetris" {
+            hatetris = true;
+        } else if let (Ok(g), true) = (arg.parse::<usize>(), generations.len() < 2) {
+            generations.push(g);
+        } else {
+            fail(format!("Unknown argument {}.", arg));
+        }
+    }
+    if hatetris {
+        games = 1;
+    }
+    if games == 0 {
+        fail(String::from("There must be at least one game."));
+    }
+
+    let has_network = |g: usize| {
+        let mut c = conf.clone();
+        c.generation = g;
+        Path::new(&c.neural_network_path()).exists()
+    };
+    let first = generations.first().copied().unwrap_or(0);
+    let last = match generations.get(1) {
+        Some(&last) => last,
+        None => (first..).take_while(|&g| has_network(g)).last().unwrap_or_else(|| {
+            fail(format!("No network for generation {} in {}.", first, conf.aeon_path()))
+        }),
+    };
+
+    let seed = init_seed(&conf.seed_path());
+    println!("Run seed: {}", seed);
+    println!("Generations {} to {}, {} games each, beam width {}", first, last, games, conf.beam_width);
+
+    let path = out.unwrap_or_else(|| {
+        let pieces = if hatetris { "hatetris" } else { "bag" };
+        format!("{}/Scores width {} {}.csv", conf.aeon_path(), conf.beam_width, pieces)
+    });
+    let mut table = String::from("generation,games,mean,stdev,min,max,scores\n");
+    println!("Generation  Games     Mean    Stdev   Min   Max");
+    for generation in first..=last {
+        conf.generation = generation;
+        let weight = load_network(&conf.neural_network_path());
+        let scores = play_games(&weight, &conf, hatetris, games);
+        let s = summarize(&scores);
+        println!(
+            "{:10}  {:5}  {:7.2}  {:7.2}  {:4}  {:4}",
+            generation, s.games, s.mean, s.stdev, s.min, s.max
+        );
+        let listed: Vec<String> = scores.iter().map(|s| s.to_string()).collect();
+        let row = format!("{},{},{},{},{},{}", generation, s.games, s.mean, s.stdev, s.min, s.max);
+        writeln!(table, "{},{}", row, listed.join(" ")).unwrap();
+        // Rewritten after every generation, so an interrupted evaluation keeps what it finished.
+        fs::write(&path, &table).unwrap_or_else(|_| panic!("Could not write {}.", path));
+    }
+    println!("Scores written to {}", path);
+}
+
+// Writes the .json, .csv and .txt replays of a search to stem with those extensions.
+
+pub fn write_replay(result: &SearchResult, conf: &SearchConf, stem: &Path) -> Vec<PathBuf> {
+    if let Some(dir) = stem.parent() {
+        fs::create_dir_all(dir).expect("Could not create replay folder.");
+    }
+    let mut written = vec![];
+    let files = [
+        ("json", replay_json(result, conf)),
+        ("csv", replay_csv(result)),
+        ("txt", replay_text(result, conf)),
+    ];
+    for (extension, text) in files {
+        let path = stem.with_extension(extension);
+        fs::write(&path, text).unwrap_or_else(|_| panic!("Could not write {}.", path.display()));
+        written.push(path);
+    }
+    written
+}
+
+// The rows of the well from top to bottom, as 0s and 1s.
+
+fn well_rows(state: &State) -> Vec<String> {
+    let g = geometry();
+    state.well()[..g.eff_height]
+        .iter()
+        .map(|row| format!("{:0width$b}", row, width = g.width))
+        .collect()
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// JSON has no NaN or infinity, so those are written as null.
+fn json_number(x: f64) -> String {
+    if x.is_finite() {
+        x.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+pub fn replay_json(result: &SearchResult, conf: &SearchConf) -> String {
+    let mut json = String::from("{\n");
+    writeln!(json, "  \"generation\": {},", conf.generation).unwrap();
+    writeln!(json, "  \"beam_width\": {},", conf.beam_width).unwrap();
+    writeln!(json, "  \"piece_selector\": {},", json_string(&conf.piece_selector.name())).unwrap();
+    writeln!(json, "  \"score\": {},", result.line.last().unwrap().score).unwrap();
+    writeln!(json, "  \"max_score\": {},", result.max_score).unwrap();
+    writeln!(json, "  \"final_depth\": {},", result.final_depth).unwrap();
+    writeln!(json, "  \"termination\": {},", json_string(&format!("{:?}", result.termination))).unwrap();
+    match line_keys(&result.line, conf) {
+        Ok(keys) => {
+            writeln!(json, "  \"hex\": {},", json_string(&encode_hex(&keys))).unwrap();
+            writeln!(json, "  \"base65536\": {},", json_string(&encode_base65536(&keys))).unwrap();
+        }
+        Err(e) => writeln!(json, "  \"replay_error\": {},", json_string(&e)).unwrap(),
+    }
+
+    json.push_str("  \"moves\": [");
+    let placements = result.placements.as_ref().ok();
+    for (m, state) in result.line.iter().enumerate() {
+        let placement = match placements.and_then(|p| p.get(m.wrapping_sub(1))) {
+            Some(p) => format!(
+                "{{\"piece\": {}, \"rotation\": {}, \"x\": {}, \"height\": {}, \"lines\": {}}}",
+                p.piece, p.rotation, p.x, p.height, p.lines
+            ),
+            None => String::from("null"),
+        };
+        let rows: Vec<String> = well_rows(state).iter().map(|r| json_string(r)).collect();
+        json.push_str(if m == 0 { "\n" } else { ",\n" });
+        write!(
+            json,
+            "    {{\"move\": {}, \"score\": {}, \"heuristic\": {}, \"placement\": {}, \"well\": [{}]}}",
+            m,
+            state.score,
+            json_number(result.heuristics[m]),
+            placement,
+            rows.join(", ")
+        )
+        .unwrap();
+    }
+    json.push_str("\n  ]\n}\n");
+    json
+}
+
+pub fn replay_csv(result: &SearchResult) -> String {
+    let mut csv = String::from("move,score,heuristic,piece,rotation,x,height,lines,well\n");
+    let placements = result.placements.as_ref().ok();
+    for (m, state) in result.line.iter().enumerate() {
+        let placement = match placements.and_then(|p| p.get(m.wrapping_sub(1))) {
+            Some(p) => format!("{},{},{},{},{}", p.piece, p.rotation, p.x, p.height, p.lines),
+            None => String::from(",,,,"),
+        };
+        let rows = well_rows(state).join("/");
+        writeln!(csv, "{},{},{},{},{}", m, state.score, result.heuristics[m], placement, rows).unwrap();
+    }
+    csv
+}
+
+pub fn replay_text(result: &SearchResult, conf: &SearchConf) -> String {
+    let mut text = String::new();
+    writeln!(text, "{}", result.line.last().unwrap().score).unwrap();
+    match line_keys(&result.line, conf) {
+        Ok(keys) => {
+            writeln!(text, "Hex: {}", encode_hex(&keys)).unwrap();
+            writeln!(text, "Base65536: {}", encode_base65536(&keys)).unwrap();
+        }
+        Err(e) => writeln!(text, "No replay: {}", e).unwrap(),
+    }
+    for (m, state) in result.line.iter().enumerate() {
+        let keyframe = StateH {
+            well: *state.well(),
+            score: state.score,
+            hash: state.hash(),
+            heuristic: (result.heuristics[m] * MULTIPLIER) as i64,
+        };
+        writeln!(text, "{}: \n{:?}", m, StatePP(keyframe)).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::WeightT;
+
+    #[test]
+    fn replays_list_every_move() {
+        let weight = WeightT::test_network();
+        let mut conf = SearchConf::testing();
+        conf.print = false;
+        conf.beam_width = 10;
+        conf.beam_depth = 4;
+        conf.max_play = 2;
+        conf.piece_selector = PieceSelector::Bag(3);
+        let result = beam_search_network(&State::new(), &weight, &conf);
+        let moves = result.line.len();
+        assert_eq!(moves, 9);
+
+        let json = replay_json(&result, &conf);
+        assert_eq!(json.matches("\"move\": ").count(), moves);
+        assert_eq!(json.matches("\"placement\": null").count(), 1);
+        assert!(json.contains("\"hex\": "));
+
+        let csv = replay_csv(&result);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), moves + 1);
+        let placements = result.placements.as_ref().unwrap();
+        for (m, row) in rows[2..].iter().enumerate() {
+            let fields: Vec<&str> = row.split(',').collect();
+            assert_eq!(fields[3], placements[m].piece.to_string());
+            assert_eq!(fields[8].split('/').count(), geometry().eff_height);
+        }
+
+        let text = replay_text(&result, &conf);
+        assert_eq!(text.lines().next().unwrap(), result.line.last().unwrap().score.to_string());
+
+        assert_eq!(json_string("a \"b\"\\\n"), "\"a \\\"b\\\"\\\\\\u000a\"");
+        assert_eq!(json_number(-0.5), "-0.5");
+        assert_eq!(json_number(f64::NEG_INFINITY), "null");
+    }
+
+    #[test]
+    fn seeded_games_repeat() {
+        let weight = WeightT::test_network();
+        let mut conf = SearchConf::testing();
+        conf.print = false;
+        conf.beam_width = 4;
+        conf.beam_depth = 10;
+        conf.max_play = 2;
+
+        let scores = play_games(&weight, &conf, false, 6);
+        assert_eq!(play_games(&weight, &conf, false, 6), scores);
+        conf.piece_selector = game_selector(false, 5);
+        let last = beam_search_network(&State::new(), &weight, &conf);
+        assert_eq!(scores[5], last.line.last().unwrap().score);
+
+        let summary = summarize(&[2, 4, 4, 4, 5, 5, 7, 9]);
+        assert_eq!((summary.games, summary.mean, summary.min, summary.max), (8, 5.0, 2, 9));
+        assert!((summary.stdev - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+        assert_eq!(summarize(&[3]).stdev, 0.0);
+    }
+}
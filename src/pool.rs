@@ -0,0 +1,325 @@
+// Parallel beam expansion.
+
+// A beam search with parents starts THREAD_NUMBER workers once and hands them every depth in turn.
+// The parents of a depth are split into batches of THREAD_BATCH, which idle workers claim one at a time,
+// so a worker that draws cheap parents simply takes more batches. Each worker keeps the best children it
+// has found in its own TopK (see topk.rs), and the buffers are merged once every batch is done.
+// The workers share one copy of the weights, the search settings and the transposition table for the
+// whole search; nothing is copied per batch.
+// Merging does not depend on which worker expanded which parent, so results do not depend on scheduling.
+// A worker that panics sends the panic back instead of its children, and expand() raises it again.
+// With a memory budget the workers spill their children to disk instead, see spill.rs.
+
+use crate::constants::{MULTIPLIER, THREAD_BATCH, THREAD_NUMBER};
+use crate::emulator::{network_heuristic, well_history};
+use crate::spill::{merge_runs, SpillBuffer};
+use crate::topk::TopK;
+use crate::transposition::TranspositionTable;
+use crate::tree::ParentTree;
+use crate::types::{SearchConf, StateH, StateP, WeightT};
+
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// Numbers the spill folders of searches that are not saved, which live in the temporary folder.
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+// Where a worker puts the children it finds: a TopK, or with a memory budget, runs on disk.
+enum Children {
+    Kept(TopK<StateP>),
+    Spilled(SpillBuffer),
+    Runs(Vec<PathBuf>),
+}
+
+impl Children {
+    fn insert(&mut self, state: StateH, parent: StateP) {
+        match self {
+            Children::Kept(best) => best.insert(state, parent.parent_index, parent),
+            Children::Spilled(buffer) => buffer.insert(state, parent),
+            Children::Runs(_) => panic!("The runs are already written."),
+        }
+    }
+
+    fn finish(self) -> Children {
+        match self {
+            Children::Spilled(buffer) => Children::Runs(buffer.finish()),
+            other => other,
+        }
+    }
+}
+
+// One depth of work: expand every parent listed in batches.
+struct Job {
+    depth: usize,
+    tree: Arc<ParentTree>,
+    batches: Arc<Vec<Vec<usize>>>,
+    next_batch: Arc<AtomicUsize>,
+}
+
+pub struct ExpansionPool {
+    jobs: Vec<Sender<Job>>,
+    results: Receiver<thread::Result<(Children, usize)>>,
+    workers: Vec<JoinHandle<()>>,
+    conf: Arc<SearchConf>,
+    spill_dir: PathBuf,
+}
+
+impl ExpansionPool {
+    pub fn new(weight: &WeightT, conf: &SearchConf, table: Option<Arc<TranspositionTable>>) -> ExpansionPool {
+        let weight = Arc::new(weight.clone());
+        let spill_dir = if conf.save {
+            PathBuf::from(conf.spill_path())
+        } else {
+            let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+            env::temp_dir().join(format!("hatetris spill {} {}", process::id(), n))
+        };
+        let conf = Arc::new(conf.clone());
+        let (result_sender, results) = channel();
+
+        let mut jobs = vec![];
+        let mut workers = vec![];
+        for w in 0..THREAD_NUMBER {
+            let (job_sender, job_receiver) = channel::<Job>();
+            let weight = weight.clone();
+            let conf = conf.clone();
+            let table = table.clone();
+            let spill_dir = spill_dir.clone();
+            let result_sender: Sender<thread::Result<(Children, usize)>> = result_sender.clone();
+
+            workers.push(thread::spawn(move || {
+                for job in job_receiver {
+                    // Without this, a panic would leave expand() waiting for this worker forever.
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let mut best = if conf.beam_memory > 0 {
+                            let name = format!("depth {} worker {}", job.depth, w);
+                            let memory = conf.beam_memory / THREAD_NUMBER;
+                            Children::Spilled(SpillBuffer::new(&spill_dir, name, memory, &conf))
+                        } else {
+                            Children::Kept(TopK::for_search(&conf))
+                        };
+                        let mut children_count = 0;
+                        loop {
+                            let b = job.next_batch.fetch_add(1, Ordering::Relaxed);
+                            if b >= job.batches.len() {
+                                break;
+                            }
+                            for &p in &job.batches[b] {
+                                children_count += expand_parent(
+                                    p,
+                                    job.tree.nodes(),
+                                    &weight,
+                                    &conf,
+                                    table.as_deref(),
+                                    &mut best,
+                                );
+                            }
+                        }
+                        (best.finish(), children_count)
+                    }));
+                    // Release the tree before reporting, so the caller can take it back.
+                    drop(job);
+                    if result_sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            }));
+            jobs.push(job_sender);
+        }
+
+        ExpansionPool {
+            jobs,
+            results,
+            workers,
+            conf,
+            spill_dir,
+        }
+    }
+
+    // Expands the beam of the tree, returning the best children from worst to best and the number
+    // of children. The tree is handed back unchanged.
+
+    pub fn expand(&self, tree: ParentTree) -> (ParentTree, Vec<(StateH, StateP)>, usize) {
+        let depth = tree.nodes()[tree.frontier()[0]].depth;
+        let batches: Vec<Vec<usize>> = tree.frontier().chunks(THREAD_BATCH).map(|c| c.to_vec()).collect();
+
+        let tree = Arc::new(tree);
+        let batches = Arc::new(batches);
+        let next_batch = Arc::new(AtomicUsize::new(0));
+        for sender in &self.jobs {
+            sender.send(Job {
+                depth,
+                tree: tree.clone(),
+                batches: batches.clone(),
+                next_batch: next_batch.clone(),
+            })
+            .unwrap();
+        }
+
+        // Spilling searches merge their workers' runs from disk and never need the beam in memory.
+        let mut best: Option<TopK<StateP>> = (self.conf.beam_memory == 0).then(|| TopK::for_search(&self.conf));
+        let mut runs = vec![];
+        let mut children_count = 0;
+        for _ in 0..self.jobs.len() {
+            let (children, count) = match self.results.recv().unwrap() {
+                Ok(result) => result,
+                Err(payload) => panic::resume_unwind(payload),
+            };
+            children_count += count;
+            match children {
+                Children::Kept(worker_best) => best.as_mut().unwrap().merge(worker_best),
+                Children::Runs(worker_runs) => runs.extend(worker_runs),
+                Children::Spilled(_) => unreachable!(),
+            }
+        }
+
+        let tree = Arc::try_unwrap(tree).unwrap_or_else(|shared| (*shared).clone());
+        match best {
+            Some(best) => (tree, best.into_vec(), children_count),
+            None => (tree, merge_runs(&runs, &self.conf), children_count),
+        }
+    }
+}
+
+impl Drop for ExpansionPool {
+    fn drop(&mut self) {
+        // Closing the job channels ends the workers. Their panics were already sent to expand().
+        self.jobs.clear();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+        if self.conf.beam_memory > 0 {
+            fs::remove_dir(&self.spill_dir).ok();
+        }
+    }
+}
+
+// Adds the children of parents[p] to best, returning how many there were.
+// The parent chain gives the history when loop prevention is on.
+
+fn expand_parent(
+    p: usize,
+    parents: &[StateP],
+    weight: &WeightT,
+    conf: &SearchConf,
+    table: Option<&TranspositionTable>,
+    best: &mut Children,
+) -> usize {
+    let parent = &parents[p];
+    let well = parent.convert_state();
+    let history = if conf.loop_prevention {
+        Some(well_history(parents, p))
+    } else {
+        None
+    };
+    let full_legal = network_heuristic(&well, weight, conf, parent.depth, history.as_ref(), table);
+    let children_count = full_legal.len();
+
+    for (node, h) in full_legal {
+        let to_insert = StateH {
+            well: *node.well(),
+            score: node.score,
+            hash: node.hash(),
+            heuristic: (h * MULTIPLIER) as i64,
+        };
+        let new_parent = StateP {
+            well: *node.well(),
+            score: node.score,
+            hash: node.hash(),
+            heuristic: h,
+            min_prev_heuristic: h.min(parent.min_prev_heuristic),
+            depth: parent.depth + 1,
+            parent_index: p,
+        };
+        best.insert(to_insert, new_parent);
+    }
+    children_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FamilyKey, PieceSelector, State};
+
+    fn root() -> StateP {
+        let state = State::new();
+        StateP {
+            well: *state.well(),
+            score: state.score,
+            hash: state.hash(),
+            heuristic: 0.0,
+            min_prev_heuristic: f64::MAX,
+            depth: 0,
+            parent_index: usize::MAX,
+        }
+    }
+
+    fn one_thread(tree: &ParentTree, weight: &WeightT, conf: &SearchConf) -> (Vec<(StateH, StateP)>, usize) {
+        let mut expected = Children::Kept(TopK::for_search(conf));
+        let mut expected_count = 0;
+        for &p in tree.frontier() {
+            expected_count += expand_parent(p, tree.nodes(), weight, conf, None, &mut expected);
+        }
+        match expected {
+            Children::Kept(best) => (best.into_vec(), expected_count),
+            _ => unreachable!(),
+        }
+    }
+
+    fn two_depths(conf: &SearchConf) {
+        let weight = WeightT::test_network();
+
+        // Two depths of parents, the second from the first's best children.
+        let pool = ExpansionPool::new(&weight, conf, None);
+        let (mut tree, best, _) = pool.expand(ParentTree::new(root()));
+        tree.advance(best.into_iter().map(|(_, p)| p).collect());
+
+        let (returned, best, count) = pool.expand(tree.clone());
+        assert_eq!(returned.nodes(), tree.nodes());
+        assert_eq!(returned.frontier(), tree.frontier());
+
+        let (expected, expected_count) = one_thread(&tree, &weight, conf);
+        assert_eq!(count, expected_count);
+        assert_eq!(best.len(), conf.beam_width);
+        assert_eq!(best, expected);
+    }
+
+    fn conf() -> SearchConf {
+        let mut conf = SearchConf::testing();
+        conf.beam_width = 25;
+        conf.max_play = 2;
+        conf.piece_selector = PieceSelector::Bag(16);
+        conf.family_key = FamilyKey::TopSurface;
+        conf.family_cap = 3;
+        conf
+    }
+
+    #[test]
+    fn pool_matches_one_thread() {
+        two_depths(&conf());
+    }
+
+    #[test]
+    fn spilled_beams_match_memory() {
+        // A few dozen children per run, so every worker writes several runs per depth.
+        let mut conf = conf();
+        conf.beam_memory = THREAD_NUMBER * 40 * std::mem::size_of::<(StateH, StateP)>();
+        two_depths(&conf);
+    }
+
+    #[test]
+    fn worker_panics_reach_the_caller() {
+        // Piece 99 does not exist, so expanding the root panics in whichever worker takes it.
+        let mut conf = conf();
+        conf.piece_selector = PieceSelector::Sequence(vec![99]);
+        let pool = ExpansionPool::new(&WeightT::test_network(), &conf, None);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.expand(ParentTree::new(root()))));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,566 @@
+// Keystroke replays in the HATETRIS format.
+
+// A piece position is a pair (i, height):
+//	i is the waveform index rot + 4 * x, where the 4x4 bounding box of the piece starts at column x - 2.
+//	height is the height of the row below the bounding box, as for waveforms.
+
+use crate::emulator::{
+    above_line_mask, get_legal, piece_placements, place_piece, position_mask, spawn_position,
+    wave_bit,
+};
+use crate::geometry::geometry;
+use crate::pieces::piece_count;
+use crate::types::{PieceSelector, Placement, SearchConf, State, StateH, StatePP, WaveT, WellT};
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Left,
+    Right,
+    Down,
+    Up,
+}
+
+impl Key {
+    // Two bits per key, as used by both HATETRIS replay encodings.
+    pub fn bits(&self) -> u8 {
+        match self {
+            Key::Left => 0,
+            Key::Right => 1,
+            Key::Down => 2,
+            Key::Up => 3,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Key {
+        match bits & 3 {
+            0 => Key::Left,
+            1 => Key::Right,
+            2 => Key::Down,
+            _ => Key::Up,
+        }
+    }
+}
+
+// Base65536 maps a pair of bytes (b1, b2) to the code point BASE65536_BLOCKS[b2] + b1,
+// and a single trailing byte b to BASE65536_SINGLE + b.
+// The 256 blocks of 256 code points are taken in order from these ranges.
+
+const BASE65536_RANGES: [(u32, u32); 10] = [
+    (0x3400, 0x4CFF),
+    (0x4E00, 0x9EFF),
+    (0xA100, 0xA3FF),
+    (0xA500, 0xA5FF),
+    (0x10600, 0x106FF),
+    (0x12000, 0x122FF),
+    (0x13000, 0x133FF),
+    (0x14400, 0x145FF),
+    (0x16800, 0x169FF),
+    (0x20000, 0x285FF),
+];
+const BASE65536_SINGLE: u32 = 0x1500;
+
+pub fn base65536_blocks() -> Vec<u32> {
+    let mut blocks = Vec::with_capacity(256);
+    for (start, end) in BASE65536_RANGES {
+        let mut block = start;
+        while block < end {
+            blocks.push(block);
+            block += 256;
+        }
+    }
+    blocks
+}
+
+// Where a key moves a piece, ignoring collisions.
+// Down is the only key that changes the height; Up rotates clockwise.
+
+pub fn key_step(position: (usize, usize), key: Key) -> Option<(usize, usize)> {
+    let (i, height) = position;
+    match key {
+        Key::Left if i >= 4 => Some((i - 4, height)),
+        Key::Right if i + 4 < geometry().wave_size => Some((i + 4, height)),
+        Key::Down => Some((i, height + 1)),
+        Key::Up => Some((i - i % 4 + (i + 1) % 4, height)),
+        _ => None,
+    }
+}
+
+// Valid positions of piece p at every height of the well.
+
+pub fn piece_masks(p: usize, well: &WellT) -> Vec<WaveT> {
+    (0..geometry().well_height)
+        .map(|h| position_mask(p, h, well))
+        .collect()
+}
+
+pub fn is_valid(masks: &[WaveT], position: (usize, usize)) -> bool {
+    let (i, h) = position;
+    h < masks.len() && masks[h] & wave_bit(i) != 0
+}
+
+// Shortest key sequence taking piece p from its spawn position to rest at the target position.
+// The sequence ends with the Down that locks the piece in place.
+// Returns None if the target cannot be reached, or if the piece would not rest there.
+
+pub fn find_path(p: usize, well: &WellT, target: (usize, usize)) -> Option<Vec<Key>> {
+    let masks = piece_masks(p, well);
+    let is_valid = |position: (usize, usize)| is_valid(&masks, position);
+
+    let start = spawn_position(p);
+    if !is_valid(start) || !is_valid(target) || is_valid((target.0, target.1 + 1)) {
+        return None;
+    }
+
+    let mut previous: FnvHashMap<(usize, usize), ((usize, usize), Key)> = FnvHashMap::default();
+    let mut queue = VecDeque::new();
+    queue.push_back(start);
+
+    while let Some(position) = queue.pop_front() {
+        if position == target {
+            let mut keys = vec![Key::Down];
+            let mut current = position;
+            while current != start {
+                let (prev, key) = previous[&current];
+                keys.push(key);
+                current = prev;
+            }
+            keys.reverse();
+            return Some(keys);
+        }
+
+        for key in [Key::Left, Key::Right, Key::Down, Key::Up] {
+            if let Some(next) = key_step(position, key) {
+                if is_valid(next) && next != start && !previous.contains_key(&next) {
+                    previous.insert(next, (position, key));
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+// Finds the resting position of piece p in state that produces child.
+
+pub fn find_placement(state: &State, p: usize, child: &State) -> Option<Placement> {
+    piece_placements(p, state)
+        .into_iter()
+        .find(|placement| placement.state == *child)
+}
+
+// Reconstructs the placement making each move of a line of consecutive wells, line[0] being the start
+// of the game. The piece for each move is the one conf.piece_selector would choose;
+// with PieceSelector::Random the pieces are not recorded, so every piece is tried.
+
+pub fn line_placements(line: &[State], conf: &SearchConf) -> Result<Vec<Placement>, String> {
+    let mut placements = vec![];
+    let mut history = FnvHashSet::default();
+
+    for d in 0..line.len().saturating_sub(1) {
+        history.insert(*line[d].well());
+
+        let pieces: Vec<usize> = if conf.piece_selector == PieceSelector::Random {
+            (0..piece_count()).collect()
+        } else {
+            let loop_history = if conf.loop_prevention {
+                Some(&history)
+            } else {
+                None
+            };
+            vec![get_legal(&line[d], &conf.piece_selector, d, loop_history).0]
+        };
+
+        match pieces
+            .into_iter()
+            .find_map(|p| find_placement(&line[d], p, &line[d + 1]))
+        {
+            Some(placement) => placements.push(placement),
+            None => {
+                return Err(format!(
+                    "No placement leads from move {} to move {}.",
+                    d,
+                    d + 1
+                ))
+            }
+        }
+    }
+
+    Ok(placements)
+}
+
+// Reconstructs the keystrokes for a line of consecutive wells, see line_placements().
+// Only a line played with PieceSelector::Hatetris from the empty well replays in HATETRIS itself.
+
+pub fn line_keys(line: &[State], conf: &SearchConf) -> Result<Vec<Key>, String> {
+    let mut keys = vec![];
+    for (d, placement) in line_placements(line, conf)?.into_iter().enumerate() {
+        let target = (placement.wave_index(), placement.height);
+        match find_path(placement.piece, line[d].well(), target) {
+            Some(mut path) => keys.append(&mut path),
+            None => return Err(format!("No key sequence reaches move {}.", d + 1)),
+        }
+    }
+
+    Ok(keys)
+}
+
+// Each hex digit holds two keys, the first in the high bits.
+// An odd number of keys is padded with Down, which does nothing once the game is over.
+// Digits are grouped in fours, as HATETRIS displays them.
+
+pub fn encode_hex(keys: &[Key]) -> String {
+    let mut digits = String::with_capacity(keys.len() / 2 + keys.len() / 8 + 1);
+    for (n, pair) in keys.chunks(2).enumerate() {
+        if n > 0 && n % 4 == 0 {
+            digits.push(' ');
+        }
+        let second = if pair.len() == 2 { pair[1] } else { Key::Down };
+        let value = (pair[0].bits() << 2) | second.bits();
+        digits.push(
+            std::char::from_digit(value as u32, 16)
+                .unwrap()
+                .to_ascii_uppercase(),
+        );
+    }
+    digits
+}
+
+// Each byte holds four keys, the first in the high bits, padded with Down as for hex.
+// Every pair of bytes becomes one Base65536 character.
+
+pub fn encode_base65536(keys: &[Key]) -> String {
+    let mut bytes = Vec::with_capacity(keys.len() / 4 + 1);
+    for quad in keys.chunks(4) {
+        let mut byte = 0;
+        for k in 0..4 {
+            byte = (byte << 2) | quad.get(k).unwrap_or(&Key::Down).bits();
+        }
+        bytes.push(byte);
+    }
+
+    let blocks = base65536_blocks();
+    let mut encoded = String::with_capacity(2 * bytes.len());
+    for pair in bytes.chunks(2) {
+        let code_point = if pair.len() == 2 {
+            blocks[pair[1] as usize] + pair[0] as u32
+        } else {
+            BASE65536_SINGLE + pair[0] as u32
+        };
+        encoded.push(char::from_u32(code_point).unwrap());
+    }
+    encoded
+}
+
+pub fn decode_hex(code: &str) -> Result<Vec<Key>, String> {
+    let mut keys = vec![];
+    for c in code.chars().filter(|c| !c.is_whitespace()) {
+        let value = match c.to_digit(16) {
+            Some(v) => v as u8,
+            None => return Err(format!("'{}' is not a hex digit.", c)),
+        };
+        keys.push(Key::from_bits(value >> 2));
+        keys.push(Key::from_bits(value));
+    }
+    Ok(keys)
+}
+
+pub fn decode_base65536(code: &str) -> Result<Vec<Key>, String> {
+    let blocks = base65536_blocks();
+    let mut bytes = vec![];
+    let mut finished = false;
+
+    for c in code.chars().filter(|c| !c.is_whitespace()) {
+        if finished {
+            return Err(String::from(
+                "Base65536 single byte before the end of the replay.",
+            ));
+        }
+        let code_point = c as u32;
+        let block = code_point - code_point % 256;
+        if block == BASE65536_SINGLE {
+            bytes.push((code_point % 256) as u8);
+            finished = true;
+        } else {
+            match blocks.iter().position(|&b| b == block) {
+                Some(b2) => {
+                    bytes.push((code_point % 256) as u8);
+                    bytes.push(b2 as u8);
+                }
+                None => return Err(format!("'{}' is not a Base65536 character.", c)),
+            }
+        }
+    }
+
+    let mut keys = Vec::with_capacity(4 * bytes.len());
+    for byte in bytes {
+        for k in (0..4).rev() {
+            keys.push(Key::from_bits(byte >> (2 * k)));
+        }
+    }
+    Ok(keys)
+}
+
+// Hex replays are recognised by their digits; anything else is read as Base65536.
+
+pub fn decode_replay(code: &str) -> Result<Vec<Key>, String> {
+    if code
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || c.is_whitespace())
+    {
+        decode_hex(code)
+    } else {
+        decode_base65536(code)
+    }
+}
+
+pub struct ReplayGame {
+    pub line: Vec<State>, // The empty well, then the well after each piece locks.
+    pub pieces: Vec<usize>,
+    pub game_over: bool,
+    pub unused_keys: usize, // Keys pressed after the game ended, usually padding.
+}
+
+// Plays keystrokes from the empty well, choosing pieces as conf would.
+// As in HATETRIS, blocked Left, Right and Up keys do nothing, and a blocked Down locks the piece.
+// Locking a piece with cells above the line ends the game.
+
+pub fn simulate(keys: &[Key], conf: &SearchConf) -> ReplayGame {
+    let mut game = ReplayGame {
+        line: vec![State::new()],
+        pieces: vec![],
+        game_over: false,
+        unused_keys: 0,
+    };
+
+    let mut state = State::new();
+    let mut history = FnvHashSet::default();
+    history.insert(*state.well());
+
+    let mut p = next_piece(&state, conf, 0, &history);
+    let mut masks = piece_masks(p, state.well());
+    let mut position = spawn_position(p);
+    game.game_over = !is_valid(&masks, position);
+
+    for &key in keys {
+        if game.game_over {
+            game.unused_keys += 1;
+            continue;
+        }
+
+        if let Some(next) = key_step(position, key) {
+            if is_valid(&masks, next) {
+                position = next;
+                continue;
+            }
+        }
+        if key != Key::Down {
+            continue;
+        }
+
+        let (i, h) = position;
+        if above_line_mask(p, h) & wave_bit(i) != 0 {
+            game.game_over = true;
+            continue;
+        }
+
+        state = place_piece(i, h, p, &state).0;
+        history.insert(*state.well());
+        game.line.push(state.clone());
+        game.pieces.push(p);
+
+        p = next_piece(&state, conf, game.pieces.len(), &history);
+        masks = piece_masks(p, state.well());
+        position = spawn_position(p);
+        game.game_over = !is_valid(&masks, position);
+    }
+
+    game
+}
+
+fn next_piece(
+    state: &State,
+    conf: &SearchConf,
+    depth: usize,
+    history: &FnvHashSet<WellT>,
+) -> usize {
+    let loop_history = if conf.loop_prevention {
+        Some(history)
+    } else {
+        None
+    };
+    get_legal(state, &conf.piece_selector, depth, loop_history).0
+}
+
+fn printable(state: &State) -> StatePP {
+    StatePP(StateH {
+        well: *state.well(),
+        score: state.score,
+        hash: state.hash(),
+        heuristic: 0,
+    })
+}
+
+// Decodes and plays a replay against the real HATETRIS piece selection.
+// The argument is either a replay code or a file containing one.
+
+fn play_replay(replay: &str) -> Result<(Vec<Key>, ReplayGame), String> {
+    let code = if Path::new(replay).exists() {
+        fs::read_to_string(replay).map_err(|e| format!("Could not read {} ({}).", replay, e))?
+    } else {
+        String::from(replay)
+    };
+    let keys = decode_replay(&code).map_err(|e| format!("Could not decode replay: {}", e))?;
+
+    let mut conf = SearchConf::testing();
+    conf.piece_selector = PieceSelector::Hatetris;
+    conf.loop_prevention = true;
+
+    let game = simulate(&keys, &conf);
+    Ok((keys, game))
+}
+
+// The well before move m of a replay, 0 being the empty well, to start a search from.
+
+pub fn replay_well(replay: &str, m: usize) -> Result<State, String> {
+    let (_, game) = play_replay(replay)?;
+    game.line
+        .get(m)
+        .cloned()
+        .ok_or_else(|| format!("The replay only has {} moves.", game.pieces.len()))
+}
+
+// Prints the outcome of a replay, and the well before a move if one is given; see replay_well().
+// A replay that cannot be read or decoded exits with status 1.
+
+pub fn verify_replay(replay: &str, seed_move: Option<usize>) {
+    let (keys, game) = match play_replay(replay) {
+        Ok(played) => played,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let last = game.line.last().unwrap();
+
+    println!("Keys: {}", keys.len());
+    println!("Pieces placed: {}", game.pieces.len());
+    println!("Game over: {}", game.game_over);
+    println!("Keys after game over: {}", game.unused_keys);
+    println!("Score: {}", last.score);
+    println!("Final well:\n{:?}", printable(last));
+
+    if let Some(m) = seed_move {
+        match game.line.get(m) {
+            Some(s) => println!("Well before move {}:\n{:?}", m, printable(s)),
+            None => println!("The replay only has {} moves.", game.pieces.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::single_move;
+
+    #[test]
+    fn base65536_has_256_blocks() {
+        let blocks = base65536_blocks();
+        assert_eq!(blocks.len(), 256);
+        for b in blocks {
+            assert_eq!(b % 256, 0);
+        }
+    }
+
+    #[test]
+    fn hex_packs_two_keys_per_digit() {
+        let keys = vec![
+            Key::Up,
+            Key::Left,
+            Key::Left,
+            Key::Down,
+            Key::Down,
+            Key::Down,
+        ];
+        assert_eq!(encode_hex(&keys), "C2A");
+
+        let keys = vec![Key::Down; 9];
+        assert_eq!(encode_hex(&keys), "AAAA A");
+    }
+
+    #[test]
+    fn every_resting_position_has_a_path() {
+        let state = State::new();
+        for p in 0..piece_count() {
+            for placement in piece_placements(p, &state) {
+                let target = (placement.wave_index(), placement.height);
+                let path = find_path(p, state.well(), target).unwrap();
+                assert_eq!(*path.last().unwrap(), Key::Down);
+            }
+        }
+    }
+
+    #[test]
+    fn line_keys_follow_the_hatetris_line() {
+        let mut conf = SearchConf::testing();
+        conf.piece_selector = PieceSelector::Hatetris;
+
+        conf.loop_prevention = false;
+
+        let mut line = vec![State::new()];
+        for d in 0..5 {
+            let children = single_move(&line[d], &conf.piece_selector, d, None);
+            line.push(children[children.len() / 2].clone());
+        }
+
+        let keys = line_keys(&line, &conf).unwrap();
+        assert!(keys.iter().filter(|&&k| k == Key::Down).count() >= line.len() - 1);
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let keys: Vec<Key> = (0..37).map(|n| Key::from_bits((n * 7 % 5) as u8)).collect();
+
+        let hex = decode_replay(&encode_hex(&keys)).unwrap();
+        assert_eq!(hex[..keys.len()], keys[..]);
+        assert!(hex[keys.len()..].iter().all(|&k| k == Key::Down));
+
+        let base = decode_replay(&encode_base65536(&keys)).unwrap();
+        assert_eq!(base[..keys.len()], keys[..]);
+        assert!(base[keys.len()..].iter().all(|&k| k == Key::Down));
+    }
+
+    #[test]
+    fn bad_codes_are_rejected() {
+        assert!(decode_replay("not a replay").is_err());
+    }
+
+    #[test]
+    fn simulated_keys_reproduce_the_line() {
+        let mut conf = SearchConf::testing();
+        conf.piece_selector = PieceSelector::Hatetris;
+        conf.loop_prevention = true;
+
+        let mut line = vec![State::new()];
+        let mut history = FnvHashSet::default();
+        for d in 0..8 {
+            history.insert(*line[d].well());
+            let children = single_move(&line[d], &conf.piece_selector, d, Some(&history));
+            line.push(children[d * 3 % children.len()].clone());
+        }
+
+        let code = encode_hex(&line_keys(&line, &conf).unwrap());
+        let game = simulate(&decode_replay(&code).unwrap(), &conf);
+        assert_eq!(game.line, line);
+        assert!(!game.game_over);
+
+        assert_eq!(replay_well(&code, 5), Ok(line[5].clone()));
+        assert!(replay_well(&code, 9).is_err());
+    }
+}
@@ -1,22 +1,31 @@
-use crate::constants::{
-    EFF_HEIGHT, MAX_ROW, MULTIPLIER, REPLAY, THREAD_BATCH, THREAD_NUMBER, VERSION,
+use crate::constants::MULTIPLIER;
+use crate::database::load_states;
+use crate::emulator::{
+    game_over, network_heuristic, network_heuristic_individual, single_move,
 };
-use crate::emulator::{network_heuristic, network_heuristic_individual, single_move};
-use crate::types::{SearchConf, State, StateH, StateP, StatePP, WeightT};
-
-use std::collections::{BTreeSet, HashSet};
+use crate::geometry::geometry;
+use crate::pool::ExpansionPool;
+use crate::replay::line_placements;
+use crate::topk::TopK;
+use crate::transposition::TranspositionTable;
+use crate::tree::ParentTree;
+use crate::types::{GameOver, PieceSelector, Placement, RowT, ScoreT, SearchConf, State, StateH, StateP, WeightT};
+
+use std::collections::HashSet;
 use std::fs::{self, File};
+use std::io::Write;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::thread::JoinHandle;
+use std::sync::Arc;
 use std::time::Instant;
 
-use fnv::{FnvHashMap, FnvHashSet};
+use fnv::FnvHashMap;
 
 use savefile::prelude::*;
 
-pub fn complete_search(starting_state: &State) -> () {
+// Breadth first search over every well, printing statistics for each depth up to max_depth.
+// See perft.rs for checking the move counts.
+
+pub fn complete_search(starting_state: &State, selector: &PieceSelector, max_depth: usize) {
     let start = Instant::now();
 
     let mut move_list = HashSet::new();
@@ -24,11 +33,11 @@ pub fn complete_search(starting_state: &State) -> () {
 
     let mut depth = 0;
 
-    while move_list.len() > 0 {
+    while !move_list.is_empty() && depth < max_depth {
         let mut new_list = HashSet::new();
         let mut children_count = 0;
         for m in &move_list {
-            let new_wells = single_move(&m);
+            let new_wells = single_move(m, selector, depth, None);
             for s in new_wells {
                 new_list.insert(s);
                 children_count += 1;
@@ -36,33 +45,36 @@ pub fn complete_search(starting_state: &State) -> () {
         }
 
         move_list = new_list.clone();
-        if move_list.len() == 0 {
+        if move_list.is_empty() {
             break;
         }
         let mut best_by_score = move_list.iter().next().unwrap().clone();
 
-        let mut families: Vec<usize> = vec![0; MAX_ROW as usize + 1];
+        let g = geometry();
+        let mut family_counts: FnvHashMap<RowT, usize> = FnvHashMap::default();
         let mut scores = vec![0; best_by_score.score as usize + 1];
         for w in move_list.iter() {
-            families[w.well[EFF_HEIGHT - 1 as usize] as usize] += 1;
+            *family_counts.entry(w.well()[g.eff_height - 1]).or_insert(0) += 1;
             while scores.len() <= w.score as usize {
                 scores.push(0);
                 best_by_score = w.clone();
             }
             scores[w.score as usize] += 1;
         }
+        let mut families: Vec<usize> = family_counts.into_values().collect();
         families.sort();
         families.reverse();
+        families.resize(10, 0);
 
         depth += 1;
 
-        println!("");
+        println!();
         println!("Depth {}", depth);
         println!("Time: {} seconds", start.elapsed().as_secs());
         println!("Total children: {}", children_count);
         println!("New well count: {}", move_list.len());
         println!("Maximum score: {:?}", best_by_score);
-        println!("Family distribution: {:?}", &families[0..10]);
+        println!("Family distribution: {:?}", &families);
         println!(
             "Score distribution: {:?}",
             scores[scores.len().max(10) - 10..].to_vec()
@@ -70,63 +82,97 @@ pub fn complete_search(starting_state: &State) -> () {
     }
 }
 
-pub fn get_keyframes_from_parents(parent_array: &Vec<StateP>) -> Vec<StateH> {
-    let mut j = parent_array.len() - 1;
-    let mut d = parent_array[j].depth;
-    let mut keyframes = Vec::with_capacity(d + 1);
-    while d > 0 {
-        d = parent_array[j].depth;
-        keyframes.push(parent_array[j].convert_state_h());
-        j = parent_array[j].parent_index;
+// Why each well of a beam has no children, most common reason first.
+// depth is the move number of the wells; with loop prevention the parent tree gives their histories.
+
+pub fn beam_game_over(
+    wells: &Vec<State>,
+    tree: Option<&ParentTree>,
+    conf: &SearchConf,
+    depth: usize,
+) -> Vec<(GameOver, usize)> {
+    let mut counts: FnvHashMap<GameOver, usize> = FnvHashMap::default();
+    if let (Some(tree), true) = (tree, conf.loop_prevention) {
+        for &slot in tree.frontier() {
+            let history = tree.history(slot);
+            let state = tree.nodes()[slot].convert_state();
+            if let Some(reason) = game_over(&state, &conf.piece_selector, depth, Some(&history)) {
+                *counts.entry(reason).or_insert(0) += 1;
+            }
+        }
+    } else {
+        for well in wells {
+            if let Some(reason) = game_over(well, &conf.piece_selector, depth, None) {
+                *counts.entry(reason).or_insert(0) += 1;
+            }
+        }
     }
 
-    return keyframes;
+    let mut counts: Vec<(GameOver, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
 }
 
-pub fn thread_parent(
-    parents: Vec<(usize, StateP)>,
-    weight: WeightT,
-    conf: SearchConf,
-    arc_is_running: Arc<Mutex<Vec<bool>>>,
-    index: usize,
-) -> JoinHandle<(Vec<(StateH, StateP)>)> {
-    let t = thread::spawn(move || {
-        let mut to_return = vec![];
-
-        for (p, parent) in parents {
-            let well = &parent.convert_state();
-            let full_legal = network_heuristic(well, &weight, &conf);
-
-            for (node, h) in full_legal {
-                let weighted_h = (h * MULTIPLIER) as i64;
-
-                let to_insert = StateH {
-                    well: node.well,
-                    score: node.score,
-                    heuristic: weighted_h,
-                };
-
-                let new_parent = StateP {
-                    well: to_insert.well.clone(),
-                    score: to_insert.score,
-                    heuristic: h,
-                    min_prev_heuristic: h.min(parent.min_prev_heuristic),
-                    depth: parent.depth + 1,
-                    parent_index: p,
-                };
-                to_return.push((to_insert, new_parent));
-            }
-        }
-        let mut is_running = arc_is_running.lock().unwrap();
-        is_running[index] = false;
-        drop(is_running);
+// A search that starts from the root starts a new parent log.
+
+fn start_tree(root: StateP, conf: &SearchConf) -> ParentTree {
+    let tree = ParentTree::new(root);
+    if conf.save {
+        fs::remove_file(conf.parent_log_path()).ok();
+        tree.save_frontier(&conf.parent_log_path());
+    }
+    tree
+}
+
+// Why a beam search stopped.
 
-        return to_return;
-    });
-    return t;
+#[derive(Clone, Debug, PartialEq)]
+pub enum Termination {
+    GameOver(Vec<(GameOver, usize)>), // No well of the beam had children; how many died of each reason.
+    DepthLimit,                       // It reached twice beam_depth.
+    TimeBudget,
+    NodeBudget,
 }
 
-pub fn beam_search_network(starting_state: &State, weight: &WeightT, conf: &SearchConf) -> f64 {
+// What one depth of a beam search found.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DepthStats {
+    pub depth: usize,
+    pub seconds: f64, // Since the start of the search.
+    pub children: usize,
+    pub wells: usize,
+    pub parents: usize, // Live parents, 0 for searches without.
+    pub best_heuristic: f64,
+    pub max_score: ScoreT,
+    pub transposition_lookups: usize,
+    pub transposition_hits: usize,
+}
+
+// The outcome of a beam search.
+//	heuristic: the goal heuristic the network is trained on, see the end of beam_search_network().
+//	final_depth: the last depth with wells left.
+//	line: the wells from the starting well to the best well of final_depth; without parents, only that well.
+//	heuristics: the network's heuristic for each well of line, as the search saw it.
+//	placements: the placement making each move of line, or why they could not be reconstructed.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub heuristic: f64,
+    pub final_depth: usize,
+    pub max_score: ScoreT,
+    pub line: Vec<State>,
+    pub heuristics: Vec<f64>,
+    pub placements: Result<Vec<Placement>, String>,
+    pub depths: Vec<DepthStats>,
+    pub termination: Termination,
+}
+
+pub fn beam_search_network(
+    starting_state: &State,
+    weight: &WeightT,
+    conf: &SearchConf,
+) -> SearchResult {
     let beam_width = conf.beam_width;
     let beam_depth = conf.beam_depth;
 
@@ -136,29 +182,27 @@ pub fn beam_search_network(starting_state: &State, weight: &WeightT, conf: &Sear
 
     let mut wells: Vec<State> = Vec::with_capacity(beam_width + 1);
     let mut depth = 0;
-    let mut parents: Vec<StateP> = if conf.parent {
-        Vec::with_capacity(3 * beam_width)
-    } else {
-        Vec::with_capacity(1)
-    };
+    let mut tree: Option<ParentTree> = None;
 
+    let starting_heuristic = network_heuristic_individual(starting_state, weight, conf);
     let starting_parent = StateP {
-        well: starting_state.well.clone(),
+        well: *starting_state.well(),
         score: starting_state.score,
-        heuristic: network_heuristic_individual(&starting_state, &weight, conf),
+        hash: starting_state.hash(),
+        heuristic: starting_heuristic,
         min_prev_heuristic: f64::MAX,
         depth: 0,
         parent_index: usize::MAX, // This would cause a panic were it ever accessed.
     };
 
-    if conf.save && !REPLAY {
+    if conf.save {
         let mut file_name = conf.move_path(depth);
         if !Path::new(&file_name).exists() {
             wells.push(starting_state.clone());
             if conf.parent {
-                parents.push(starting_parent);
+                tree = Some(start_tree(starting_parent.clone(), conf));
             }
-            save_file(&file_name, VERSION, &wells).unwrap();
+            save_file(&file_name, geometry().version(), &wells).unwrap();
         } else {
             while Path::new(&file_name).exists() {
                 depth += 1;
@@ -167,188 +211,117 @@ pub fn beam_search_network(starting_state: &State, weight: &WeightT, conf: &Sear
 
             depth -= 1;
             file_name = conf.move_path(depth);
-            wells = load_file(&file_name, VERSION).unwrap();
+            if conf.parent && !Path::new(&conf.parent_log_path()).exists() {
+                // Earlier versions saved the whole tree to a parent_N.bin per depth instead.
+                let old_log = format!("{}/parent_{}.bin", conf.replay_path(), depth);
+                if Path::new(&old_log).exists() {
+                    panic!(
+                        "{} was saved by an earlier version, which kept its parents in parent_N.bin files; \
+                         it cannot be resumed. Move {} away to start the generation again.",
+                        old_log,
+                        conf.replay_path()
+                    );
+                }
+                panic!("Could not find the parent log {}.", conf.parent_log_path());
+            }
+            wells = load_states(&file_name);
             println!("Loaded {} positions from depth {}", wells.len(), depth);
 
             if conf.parent {
-                let parent_file_name = conf.parent_path(depth);
-                parents = load_file(&parent_file_name, VERSION).unwrap();
-                println!("Loaded {} parents from depth {}", parents.len(), depth);
+                let loaded = ParentTree::load(&conf.parent_log_path(), depth);
+                println!("Loaded {} parents from depth {}", loaded.len(), depth);
+                tree = Some(loaded);
             }
         }
     } else {
         wells.push(starting_state.clone());
         if conf.parent {
-            parents.push(starting_parent);
+            tree = Some(start_tree(starting_parent, conf));
         }
     }
 
+    // Shared across depths: the same well is often reached again deeper in the search.
+    let table = if conf.transposition_size > 0 {
+        Some(Arc::new(TranspositionTable::new(conf.transposition_size)))
+    } else {
+        None
+    };
+
+    let pool = if conf.parent {
+        Some(ExpansionPool::new(weight, conf, table.clone()))
+    } else {
+        None
+    };
+
     let start = Instant::now();
-    let mut max_score = 0;
+    let mut max_score = starting_state.score;
     let mut return_heuristic: f64 = -1.0;
     let mut final_depth = 0;
+    let mut final_heuristic: f64 = -1.0;
+    let mut best_well = starting_state.clone();
+    let mut nodes = 0;
+    let mut depths = vec![];
+    let mut termination = Termination::DepthLimit;
 
-    while wells.len() > 0 && depth < beam_depth.saturating_mul(2) {
+    while !wells.is_empty() && depth < beam_depth.saturating_mul(2) {
         depth += 1;
-        let mut new_wells: BTreeSet<StateH> = BTreeSet::new();
-        let mut first_entry: StateH = StateH::new();
-
-        let mut new_parents: Vec<StateP> = parents.clone();
+        // The beam, from the worst well to the best.
+        let mut new_wells: Vec<StateH> = vec![];
+        let mut new_parents: Vec<StateP> = vec![];
 
         let mut children_count = 0;
-        let mut length = 0;
-        let mut best_heuristic = -1.0;
-
-        if conf.parent {
-            let mut parent_array = vec![vec![]; 1];
-            for (p, parent) in parents.iter().enumerate() {
-                if parent.depth != depth - 1 {
-                    continue;
-                } else {
-                    let len = parent_array.len();
-                    if parent_array[len - 1].len() == THREAD_BATCH {
-                        parent_array.push(vec![(p, parent.clone())]);
-                    } else {
-                        parent_array[len - 1].push((p, parent.clone()));
-                    }
-                }
-            }
+        let mut best_heuristic: f64 = -1.0;
 
-            let arc_is_running = Arc::new(Mutex::new(vec![false; THREAD_NUMBER]));
-            let mut thread_list: Vec<JoinHandle<Vec<(StateH, StateP)>>> = vec![];
-            let mut interval = 0;
-            while interval < parent_array.len() || thread_list.len() > 0 {
-                let is_running = arc_is_running.lock().unwrap();
-                let mut to_merge = None;
-                for t in 0..THREAD_NUMBER {
-                    if t == thread_list.len() || !is_running[t] {
-                        to_merge = Some(t);
-                        break;
-                    }
-                }
-                drop(is_running);
-
-                if to_merge.is_some() && thread_list.len() > to_merge.unwrap() {
-                    let thread_result = thread_list.remove(to_merge.unwrap()).join().unwrap();
-
-                    children_count += thread_result.len();
-                    for (state, parent) in thread_result {
-                        if length == 1 {
-                            first_entry = new_wells.iter().next().unwrap().clone();
-                        };
-
-                        if ((length == beam_width && state.heuristic > first_entry.heuristic)
-                            || length < beam_width)
-                            && !new_wells.contains(&state)
-                        {
-                            let tmp_heuristic = state.heuristic as f64 / (MULTIPLIER as f64);
-                            if tmp_heuristic > best_heuristic {
-                                best_heuristic = tmp_heuristic;
-                            };
-
-                            new_wells.insert(state);
-                            new_parents.push(parent);
-
-                            if length == beam_width {
-                                new_wells.remove(&first_entry);
-                                first_entry = new_wells.iter().next().unwrap().clone();
-                            } else {
-                                length += 1;
-                            }
-                        }
-                    }
-                }
-
-                if to_merge.is_some() && interval < parent_array.len() {
-                    let index = to_merge.unwrap();
-                    let cloned_arc_is_running = arc_is_running.clone();
-                    thread_list.insert(
-                        index,
-                        thread_parent(
-                            parent_array[interval].clone(),
-                            weight.clone(),
-                            conf.clone(),
-                            cloned_arc_is_running,
-                            index,
-                        ),
-                    );
-                    interval += 1;
+        if let Some(pool) = &pool {
+            let (returned, best, count) = pool.expand(tree.take().unwrap());
+            tree = Some(returned);
+            children_count = count;
 
-                    let mut is_running = arc_is_running.lock().unwrap();
-                    is_running[index] = true;
-                    drop(is_running);
-                }
+            for (state, parent) in best {
+                best_heuristic = state.heuristic as f64 / MULTIPLIER;
+                new_wells.push(state);
+                new_parents.push(parent);
             }
         } else {
-            for well in wells.iter() {
-                let full_legal = network_heuristic(well, weight, conf);
+            let mut best = TopK::for_search(conf);
+            for (w, well) in wells.iter().enumerate() {
+                let full_legal =
+                    network_heuristic(well, weight, conf, depth - 1, None, table.as_deref());
                 children_count += full_legal.len();
 
                 for (node, h) in full_legal {
                     best_heuristic = best_heuristic.max(h);
 
-                    let weighted_h = (h * MULTIPLIER) as i64;
-
                     let to_insert = StateH {
-                        well: node.well,
+                        well: *node.well(),
                         score: node.score,
-                        heuristic: weighted_h,
+                        hash: node.hash(),
+                        heuristic: (h * MULTIPLIER) as i64,
                     };
-
-                    children_count += 1;
-
-                    if length == 1 {
-                        first_entry = new_wells.iter().next().unwrap().clone();
-                    };
-
-                    if ((length == beam_width && to_insert.heuristic > first_entry.heuristic)
-                        || length < beam_width)
-                        && !new_wells.contains(&to_insert)
-                    {
-                        new_wells.insert(to_insert.clone());
-                        length += 1;
-
-                        if length >= beam_width {
-                            new_wells.remove(&first_entry);
-                            length -= 1;
-                            first_entry = new_wells.iter().next().unwrap().clone();
-                        }
-                    }
+                    best.insert(to_insert, w, ());
                 }
             }
+            new_wells = best.into_vec().into_iter().map(|(state, _)| state).collect();
         }
 
-        if conf.parent && new_wells.len() > 0 {
-            let mut index_hash_set =
-                FnvHashSet::with_capacity_and_hasher(2 * length, Default::default());
-            for i in 0..new_parents.len() {
-                if new_parents[i].depth == depth
-                    && new_wells.contains(&new_parents[i].convert_state_h())
-                {
-                    let mut j = i;
-                    let mut d = depth;
-                    while d > 0 {
-                        d = new_parents[j].depth;
-                        index_hash_set.insert(j);
-                        j = new_parents[j].parent_index;
-                    }
-                }
+        if let (Some(tree), true) = (tree.as_mut(), !new_parents.is_empty()) {
+            tree.advance(new_parents);
+        }
+
+        // The game is over when no well in the beam has children; record how each of them died.
+        if new_wells.is_empty() {
+            let game_overs = beam_game_over(&wells, tree.as_ref(), conf, depth - 1);
+            if conf.print {
+                println!();
+                println!("Game over at depth {}: {:?}", depth - 1, game_overs);
             }
-            let mut indices: Vec<usize> = index_hash_set.drain().collect();
-            indices.sort();
-
-            let mut index_hash_map =
-                FnvHashMap::with_capacity_and_hasher(indices.len(), Default::default());
-            index_hash_map.insert(usize::MAX, usize::MAX);
-
-            parents.clear();
-            for (new_i, old_i) in indices.iter().enumerate() {
-                let mut new_parent = new_parents[*old_i].clone();
-                new_parent.parent_index = *index_hash_map.get(&new_parent.parent_index).unwrap();
-                parents.push(new_parent);
-                index_hash_map.insert(*old_i, new_i);
+            if conf.save {
+                let mut f = File::create(conf.game_over_path()).unwrap();
+                for (reason, count) in &game_overs {
+                    writeln!(f, "{} {:?}", count, reason).unwrap();
+                }
             }
-            parents.dedup();
+            termination = Termination::GameOver(game_overs);
         }
 
         wells.clear();
@@ -356,31 +329,47 @@ pub fn beam_search_network(starting_state: &State, weight: &WeightT, conf: &Sear
             wells.push(State::convert(w));
         }
 
-        if wells.len() == 0 {
+        if wells.is_empty() {
             break;
         } else {
             final_depth = depth;
+            final_heuristic = best_heuristic;
+            best_well = wells[wells.len() - 1].clone();
             if depth == beam_depth {
                 return_heuristic = best_heuristic;
             }
         }
 
+        // The parent log first, so every move file's depth is in the log when the search resumes.
         if conf.save {
-            let file_name = conf.move_path(depth);
-            save_file(&file_name, VERSION, &wells).unwrap();
-
-            if conf.parent {
-                let parent_file_name = conf.parent_path(depth);
-                save_file(&parent_file_name, VERSION, &parents).unwrap();
+            if let Some(tree) = &tree {
+                tree.save_frontier(&conf.parent_log_path());
             }
+
+            let file_name = conf.move_path(depth);
+            save_file(&file_name, geometry().version(), &wells).unwrap();
         }
 
+        let (lookups, hits) = table.as_ref().map_or((0, 0), |t| t.take_stats());
+        let stats = DepthStats {
+            depth,
+            seconds: start.elapsed().as_secs_f64(),
+            children: children_count,
+            wells: wells.len(),
+            parents: tree.as_ref().map_or(0, |t| t.len()),
+            best_heuristic,
+            max_score: wells.iter().map(|w| w.score).max().unwrap(),
+            transposition_lookups: lookups,
+            transposition_hits: hits,
+        };
+        max_score = max_score.max(stats.max_score);
+
         if conf.print {
-            let mut families: Vec<usize> = vec![0; MAX_ROW as usize + 1];
+            let mut family_counts: FnvHashMap<u64, usize> = FnvHashMap::default();
             let mut scores = vec![0; wells[0].score as usize + 1];
             let mut best_by_score = wells[0].clone();
             for w in wells.iter() {
-                families[w.well[(EFF_HEIGHT - 1) as usize] as usize] += 1;
+                *family_counts.entry(conf.family_key.family(w.well())).or_insert(0) += 1;
                 if w.score >= best_by_score.score {
                     best_by_score = w.clone();
                     while scores.len() <= w.score as usize {
@@ -389,83 +378,220 @@ pub fn beam_search_network(starting_state: &State, weight: &WeightT, conf: &Sear
                 }
                 scores[w.score as usize] += 1;
             }
+            let mut families: Vec<usize> = family_counts.into_values().collect();
             families.sort();
             families.reverse();
-
-            if scores.len() - 1 > max_score {
-                max_score = scores.len() - 1
-            };
+            families.resize(10, 0);
 
             let score_best_h = StateH {
-                well: best_by_score.well.clone(),
-                score: best_by_score.score.clone(),
+                well: *best_by_score.well(),
+                score: best_by_score.score,
+                hash: best_by_score.hash(),
                 heuristic: (network_heuristic_individual(&best_by_score, weight, conf)
                     * 1_000_000.0) as i64,
             };
 
             let worst = wells[0].clone();
             let worst_h = StateH {
-                well: worst.well.clone(),
-                score: worst.score.clone(),
+                well: *worst.well(),
+                score: worst.score,
+                hash: worst.hash(),
                 heuristic: (network_heuristic_individual(&worst, weight, conf) * 1_000_000.0)
                     as i64,
             };
 
             let best = wells[wells.len() - 1].clone();
             let best_h = StateH {
-                well: best.well.clone(),
-                score: best.score.clone(),
+                well: *best.well(),
+                score: best.score,
+                hash: best.hash(),
                 heuristic: (network_heuristic_individual(&best, weight, conf) * 1_000_000.0) as i64,
             };
 
             let end = start.elapsed().as_secs();
 
-            println!("");
+            println!();
             println!("Depth {}", depth);
             println!("Time: {} seconds", end);
-            if conf.parent {
-                println!("Total parents: {}", parents.len());
+            if tree.is_some() {
+                println!("Total parents: {}", stats.parents);
             }
             println!("Total children: {}", children_count);
             println!("New well count: {}", wells.len());
             println!("Maximum score: {:?}", score_best_h);
             println!("Worst heuristic: {:?}", worst_h);
             println!("Best heuristic: {:?}", best_h);
-            println!("Family distribution: {:?}", &families[0..10]);
+            println!("Family distribution: {:?}", &families);
             println!(
                 "Score distribution: {:?}",
                 scores[scores.len().max(10) - 10..].to_vec()
             );
+            if table.is_some() {
+                println!(
+                    "Transposition hits: {} of {} ({:.1}%)",
+                    hits,
+                    lookups,
+                    100.0 * hits as f64 / lookups.max(1) as f64
+                );
+            }
         }
-    }
-
-    use std::io::Write;
-
-    if conf.print && REPLAY {
-        let args = std::env::args();
-        let fname = "replay/replay_{}.txt".replace("{}", &args.skip(2).next().unwrap());
-        let mut f = File::create(fname).unwrap();
-        let keyframes = get_keyframes_from_parents(&parents);
-        writeln!(f, "{}", parents.last().unwrap().score).unwrap();
-        for (i, k) in keyframes.into_iter().rev().enumerate() {
-            writeln!(f, "{i}: \n{:?}", StatePP(k)).unwrap();
+        depths.push(stats);
+
+        nodes += children_count;
+        let out_of_time = conf
+            .time_budget
+            .is_some_and(|budget| start.elapsed() >= budget);
+        let out_of_nodes = conf.node_budget > 0 && nodes >= conf.node_budget;
+        if (out_of_time || out_of_nodes) && depth < beam_depth.saturating_mul(2) {
+            termination = if out_of_time {
+                Termination::TimeBudget
+            } else {
+                Termination::NodeBudget
+            };
+            if conf.print {
+                println!();
+                println!(
+                    "Search budget spent at depth {} after {} children.",
+                    depth, nodes
+                );
+            }
+            break;
         }
-        // die here, no consequences
-        panic!("See replay");
     }
 
+    let (line, heuristics): (Vec<State>, Vec<f64>) = match &tree {
+        Some(tree) => tree
+            .line(*tree.frontier().last().unwrap())
+            .iter()
+            .map(|p| (p.convert_state(), p.heuristic))
+            .unzip(),
+        None if final_depth == 0 => (vec![best_well], vec![starting_heuristic]),
+        None => (vec![best_well], vec![final_heuristic]),
+    };
+
     // The scaling factor adjusts the goal heuristic by the depth of the beam, for a much smoother transition.
     // If final_depth < beam_depth, return -1.
     // If final_depth > beam_depth * 2, return return_heuristic.
     // Else, return the linear interpolation between return_heuristic and -1.
-
-    if final_depth <= beam_depth || beam_depth == 0 {
-        return -1.0;
+    // A search stopped by its budget after beam_depth returns return_heuristic; before, it falls back to the above.
+
+    let out_of_budget = matches!(
+        termination,
+        Termination::TimeBudget | Termination::NodeBudget
+    );
+    let heuristic = if out_of_budget && final_depth >= beam_depth {
+        return_heuristic
+    } else if final_depth <= beam_depth || beam_depth == 0 {
+        -1.0
     } else if final_depth > beam_depth.saturating_mul(2) {
-        return return_heuristic;
+        return_heuristic
     } else {
         let f = final_depth as f64;
         let b = beam_depth as f64;
-        return ((f - b) * return_heuristic + (2.0 * b - f) * -1.0) / b;
+        ((f - b) * return_heuristic + -(2.0 * b - f)) / b
+    };
+    let placements = line_placements(&line, conf);
+    SearchResult {
+        heuristic,
+        final_depth,
+        max_score,
+        line,
+        heuristics,
+        placements,
+        depths,
+        termination,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beam_game_over_counts_each_well() {
+        let g = geometry();
+        let mut conf = SearchConf::testing();
+        conf.parent = false;
+        conf.piece_selector = PieceSelector::Sequence(vec![2]);
+
+        let mut shaft = State::new();
+        for row in 0..g.eff_height {
+            shaft.set_row(row, g.max_row >> 1);
+        }
+        let wells = vec![shaft.clone(), shaft, State::new()];
+
+        let reasons = beam_game_over(&wells, None, &conf, 0);
+        assert_eq!(reasons, vec![(GameOver::LockedAboveLine { piece: 2 }, 2)]);
+
+        // A search from a dead well ends before its first move.
+        conf.print = false;
+        let result = beam_search_network(&wells[0], &WeightT::zero(), &conf);
+        assert_eq!((result.final_depth, result.line.len()), (0, 1));
+        assert_eq!(result.placements, Ok(vec![]));
+        assert_eq!(
+            result.termination,
+            Termination::GameOver(vec![(GameOver::LockedAboveLine { piece: 2 }, 1)])
+        );
+    }
+
+    #[test]
+    fn parent_search_repeats_exactly() {
+        let weight = WeightT::test_network();
+        let mut conf = SearchConf::testing();
+        conf.print = false;
+        conf.beam_width = 20;
+        conf.beam_depth = 3;
+        conf.max_play = 2;
+        conf.piece_selector = PieceSelector::Bag(1);
+
+        let first = beam_search_network(&State::new(), &weight, &conf);
+        assert!(first.heuristic > -1.0);
+        assert_eq!(first.final_depth, 6);
+        assert_eq!(first.line.len(), 7);
+        assert_eq!(first.heuristics.len(), 7);
+        assert_eq!(first.depths.len(), 6);
+        assert_eq!(first.termination, Termination::DepthLimit);
+        assert_eq!(first.placements.as_ref().map(|p| p.len()), Ok(6));
+        // Everything but the timings.
+        let untimed = |mut result: SearchResult| {
+            result.depths.iter_mut().for_each(|d| d.seconds = 0.0);
+            result
+        };
+        assert_eq!(
+            untimed(beam_search_network(&State::new(), &weight, &conf)),
+            untimed(first)
+        );
+    }
+
+    #[test]
+    fn budget_stops_with_the_line_so_far() {
+        let weight = WeightT::test_network();
+        let mut conf = SearchConf::testing();
+        conf.print = false;
+        conf.beam_width = 10;
+        conf.beam_depth = 20;
+        conf.max_play = 2;
+        conf.piece_selector = PieceSelector::Bag(1);
+
+        let full = beam_search_network(&State::new(), &weight, &conf);
+        conf.node_budget = 50;
+        let result = beam_search_network(&State::new(), &weight, &conf);
+        let depth = result.final_depth;
+        assert!(depth > 0 && depth < full.final_depth);
+        assert_eq!(result.line.len(), depth + 1);
+        assert_eq!(result.line[0], State::new());
+        assert_eq!(result.heuristic, -1.0); // Stopped before beam_depth, like a lost search.
+        assert_eq!(result.termination, Termination::NodeBudget);
+        let children =
+            |r: &SearchResult| r.depths.iter().map(|d| d.children).collect::<Vec<usize>>();
+        assert_eq!(children(&result), children(&full)[..depth]);
+
+        conf.node_budget = 0;
+        conf.time_budget = Some(std::time::Duration::ZERO);
+        let result = beam_search_network(&State::new(), &weight, &conf);
+        assert_eq!(
+            (result.final_depth, result.termination),
+            (1, Termination::TimeBudget)
+        );
     }
 }
@@ -0,0 +1,129 @@
+// Random seeds, for reproducible runs.
+
+// There is one run seed, recorded in the aeon folder as Seed.txt.
+// The SEED environment variable overrides it for a new aeon; otherwise it is drawn at random:
+//		SEED=1234 cargo run --release
+// Every random choice draws from a generator seeded by the run seed and by what the choice is for,
+// never from a shared generator, so results do not depend on how the threads are scheduled
+// and any one generation can be re-run on its own.
+
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+use fnv::FnvHasher;
+
+use std::env;
+use std::fs;
+use std::hash::Hasher;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// What a random choice is for; the first key passed to seed_for().
+pub const WEIGHT_STREAM: u64 = 0;
+pub const PIECE_STREAM: u64 = 1;
+pub const DATA_STREAM: u64 = 2;
+pub const GAME_STREAM: u64 = 3;
+
+static SEED: OnceLock<u64> = OnceLock::new();
+
+fn env_seed() -> Option<u64> {
+    env::var("SEED").ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("SEED must be a number, not {}.", value))
+    })
+}
+
+// The run seed, drawn from SEED or at random if init_seed() has not been called.
+
+pub fn run_seed() -> u64 {
+    *SEED.get_or_init(|| env_seed().unwrap_or_else(|| thread_rng().gen()))
+}
+
+// Sets the run seed from the seed file at path, creating the file if needed.
+// Must be called before anything random happens.
+
+pub fn init_seed(path: &str) -> u64 {
+    let recorded: Option<u64> = if Path::new(path).exists() {
+        let text = fs::read_to_string(path).expect("Could not read seed file.");
+        Some(text.trim().parse().expect("Could not parse seed file."))
+    } else {
+        None
+    };
+
+    let seed = match (env_seed(), recorded) {
+        (Some(s), Some(r)) if s != r => panic!(
+            "SEED is {}, but this aeon was run with seed {} (see {}).",
+            s, r, path
+        ),
+        (_, Some(r)) => r,
+        (Some(s), None) => s,
+        (None, None) => thread_rng().gen(),
+    };
+
+    if SEED.set(seed).is_err() && run_seed() != seed {
+        panic!("The run seed was used before it was read from {}.", path);
+    }
+    if recorded.is_none() {
+        fs::write(path, format!("{}\n", seed)).expect("Could not write seed file.");
+    }
+    seed
+}
+
+pub fn seed_for(keys: &[u64]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(run_seed());
+    for &key in keys {
+        hasher.write_u64(key);
+    }
+    hasher.finish()
+}
+
+// A hash of the keys alone, for seeds given on the command line that must not depend on the run seed.
+
+pub fn hash_keys(keys: &[u64]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    for &key in keys {
+        hasher.write_u64(key);
+    }
+    hasher.finish()
+}
+
+pub fn seeded_rng(keys: &[u64]) -> StdRng {
+    StdRng::seed_from_u64(seed_for(keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::get_legal;
+    use crate::types::{PieceSelector, State};
+
+    use std::thread;
+
+    #[test]
+    fn seeds_depend_on_every_key() {
+        assert_eq!(seed_for(&[PIECE_STREAM, 3]), seed_for(&[PIECE_STREAM, 3]));
+        assert_ne!(seed_for(&[PIECE_STREAM, 3]), seed_for(&[PIECE_STREAM, 4]));
+        assert_ne!(seed_for(&[PIECE_STREAM, 3]), seed_for(&[DATA_STREAM, 3]));
+        assert_ne!(seed_for(&[PIECE_STREAM]), seed_for(&[PIECE_STREAM, 0]));
+    }
+
+    #[test]
+    fn random_pieces_do_not_depend_on_threads() {
+        let pieces = |depths: Vec<usize>| -> Vec<usize> {
+            depths
+                .into_iter()
+                .map(|d| get_legal(&State::new(), &PieceSelector::Random, d, None).0)
+                .collect()
+        };
+
+        let expected = pieces((0..64).collect());
+        let handles: Vec<_> = (0..4)
+            .map(|_| thread::spawn(move || pieces((0..64).collect())))
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+}
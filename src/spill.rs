@@ -0,0 +1,265 @@
+// External-memory beam selection, for beams too wide to hold every child in memory.
+
+// With a memory budget (SearchConf::beam_memory), each worker collects children in a buffer of
+// a fixed size instead of a TopK. When the buffer fills it is sorted from best to worst, cut down
+// to the children a TopK would keep, and written to disk as a run; once every parent is expanded
+// the runs of all workers are merged, reading each run in order, and the best children are taken.
+// Only the buffers and one record per run are in memory while the children are selected.
+// The budget covers the buffers alone. Outside it, and still in memory, are:
+//	the chosen beam, beam_width (StateH, StateP) pairs while it is merged and beam_width StateP after;
+//	the parent tree (tree.rs), every live ancestor of the beam as a StateP;
+//	the transposition table, transposition_size entries of a hash, a well and a heuristic;
+//	the network weights, shared by the workers, and each worker's own children of one parent.
+// The selection is the same as with TopKs, family cap included: taking children from best to worst
+// while their family has room, the child with the lowest parent index first among equal children.
+// A run holds fixed-size records, see RECORD_BYTES, and is deleted as soon as it is merged.
+
+use crate::constants::MAX_EFF_HEIGHT;
+use crate::topk::Greedy;
+use crate::types::{RowT, ScoreT, SearchConf, StateH, StateP};
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+const ROW_BYTES: usize = size_of::<RowT>();
+const SCORE_BYTES: usize = size_of::<ScoreT>();
+
+// The well, score, hash, heuristic, min_prev_heuristic, depth and parent_index of a StateP.
+pub const RECORD_BYTES: usize = MAX_EFF_HEIGHT * ROW_BYTES + SCORE_BYTES + 5 * 8;
+
+pub fn write_record(writer: &mut impl Write, parent: &StateP) {
+    let mut record = Vec::with_capacity(RECORD_BYTES);
+    for row in parent.well {
+        record.extend_from_slice(&row.to_le_bytes());
+    }
+    record.extend_from_slice(&parent.score.to_le_bytes());
+    record.extend_from_slice(&parent.hash.to_le_bytes());
+    record.extend_from_slice(&parent.heuristic.to_le_bytes());
+    record.extend_from_slice(&parent.min_prev_heuristic.to_le_bytes());
+    record.extend_from_slice(&(parent.depth as u64).to_le_bytes());
+    record.extend_from_slice(&(parent.parent_index as u64).to_le_bytes());
+    writer.write_all(&record).expect("Could not write beam run.");
+}
+
+pub fn read_record(reader: &mut impl Read) -> Option<StateP> {
+    let mut record = [0; RECORD_BYTES];
+    if reader.read_exact(&mut record).is_err() {
+        return None;
+    }
+
+    let mut at = 0;
+    let mut take = |n: usize| {
+        at += n;
+        &record[at - n..at]
+    };
+    let mut well = [0; MAX_EFF_HEIGHT];
+    for row in well.iter_mut() {
+        *row = RowT::from_le_bytes(take(ROW_BYTES).try_into().unwrap());
+    }
+    let score = ScoreT::from_le_bytes(take(SCORE_BYTES).try_into().unwrap());
+    let hash = u64::from_le_bytes(take(8).try_into().unwrap());
+    let heuristic = f64::from_le_bytes(take(8).try_into().unwrap());
+    let min_prev_heuristic = f64::from_le_bytes(take(8).try_into().unwrap());
+    let depth = u64::from_le_bytes(take(8).try_into().unwrap()) as usize;
+    let parent_index = u64::from_le_bytes(take(8).try_into().unwrap()) as usize;
+
+    Some(StateP {
+        well,
+        score,
+        hash,
+        heuristic,
+        min_prev_heuristic,
+        depth,
+        parent_index,
+    })
+}
+
+// Best first; equal children with the lowest parent index first.
+fn best_first(a: (&StateH, &StateP), b: (&StateH, &StateP)) -> Ordering {
+    b.0.cmp(a.0).then(a.1.parent_index.cmp(&b.1.parent_index))
+}
+
+// One worker's children, spilled to runs in dir named after the worker.
+
+pub struct SpillBuffer {
+    dir: PathBuf,
+    name: String,
+    entries: usize,
+    conf: SearchConf,
+    buffer: Vec<(StateH, StateP)>,
+    runs: Vec<PathBuf>,
+}
+
+impl SpillBuffer {
+    // A buffer of about memory bytes.
+    pub fn new(dir: &Path, name: String, memory: usize, conf: &SearchConf) -> SpillBuffer {
+        let entries = (memory / size_of::<(StateH, StateP)>()).max(1);
+        SpillBuffer {
+            dir: dir.to_path_buf(),
+            name,
+            entries,
+            conf: conf.clone(),
+            buffer: Vec::with_capacity(entries),
+            runs: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, state: StateH, parent: StateP) {
+        self.buffer.push((state, parent));
+        if self.buffer.len() == self.entries {
+            self.spill();
+        }
+    }
+
+    fn spill(&mut self) {
+        self.buffer.sort_unstable_by(|a, b| best_first((&a.0, &a.1), (&b.0, &b.1)));
+
+        fs::create_dir_all(&self.dir).expect("Could not create beam run folder.");
+        let path = self.dir.join(format!("{} run {}.bin", self.name, self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path).expect("Could not create beam run."));
+        let mut greedy = Greedy::for_search(&self.conf);
+        for (state, parent) in self.buffer.drain(..) {
+            if greedy.is_full() {
+                break;
+            }
+            if greedy.take(&state) {
+                write_record(&mut writer, &parent);
+            }
+        }
+        writer.flush().expect("Could not write beam run.");
+        self.runs.push(path);
+    }
+
+    // Spills what is left and returns the runs.
+    pub fn finish(mut self) -> Vec<PathBuf> {
+        if !self.buffer.is_empty() {
+            self.spill();
+        }
+        self.runs
+    }
+}
+
+// Merges runs into the beam, from the worst child to the best, and deletes them.
+
+pub fn merge_runs(runs: &[PathBuf], conf: &SearchConf) -> Vec<(StateH, StateP)> {
+    let mut readers: Vec<BufReader<File>> = runs
+        .iter()
+        .map(|path| BufReader::new(File::open(path).expect("Could not open beam run.")))
+        .collect();
+
+    // The heap holds the next record of each run; Reverse makes the best one come out first.
+    let mut heap = BinaryHeap::new();
+    for (r, reader) in readers.iter_mut().enumerate() {
+        if let Some(parent) = read_record(reader) {
+            heap.push(Reverse(Next(parent.convert_state_h(), parent, r)));
+        }
+    }
+
+    let mut greedy = Greedy::for_search(conf);
+    let mut beam = vec![];
+    while let Some(Reverse(Next(state, parent, r))) = heap.pop() {
+        if greedy.is_full() {
+            break;
+        }
+        if let Some(next) = read_record(&mut readers[r]) {
+            heap.push(Reverse(Next(next.convert_state_h(), next, r)));
+        }
+        if greedy.take(&state) {
+            beam.push((state, parent));
+        }
+    }
+
+    drop(readers);
+    for path in runs {
+        fs::remove_file(path).expect("Could not delete beam run.");
+    }
+    beam.reverse();
+    beam
+}
+
+// A run's next record, ordered best first.
+struct Next(StateH, StateP, usize);
+
+impl PartialEq for Next {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Next {}
+
+impl Ord for Next {
+    fn cmp(&self, other: &Self) -> Ordering {
+        best_first((&self.0, &self.1), (&other.0, &other.1)).then(self.2.cmp(&other.2))
+    }
+}
+
+impl PartialOrd for Next {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::geometry;
+    use crate::topk::TopK;
+    use crate::types::FamilyKey;
+    use crate::zobrist::well_hash;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_parent(rng: &mut StdRng) -> StateP {
+        let g = geometry();
+        let mut well = [0; MAX_EFF_HEIGHT];
+        well[g.eff_height - 1] = rng.gen_range(1..6);
+        well[g.eff_height - 2] = rng.gen_range(0..3);
+        let hash = well_hash(&well);
+        let parent_index = rng.gen_range(0..1000);
+        // As in a search, one parent has one child per well.
+        StateP {
+            well,
+            score: rng.gen_range(0..3),
+            hash,
+            // Equal wells always have equal heuristics.
+            heuristic: (hash % 23) as f64 / 7.0,
+            min_prev_heuristic: parent_index as f64 / 3.0,
+            depth: parent_index % 50,
+            parent_index,
+        }
+    }
+
+    #[test]
+    fn runs_merge_to_the_top_k() {
+        let dir = std::env::temp_dir().join(format!("hatetris spill test {}", std::process::id()));
+        let mut rng = StdRng::seed_from_u64(18);
+        for trial in 0..50 {
+            let mut conf = SearchConf::testing();
+            conf.beam_width = rng.gen_range(1..20);
+            conf.family_key = [FamilyKey::BottomRow, FamilyKey::TopSurface][trial % 2];
+            conf.family_cap = trial % 3;
+            let parents: Vec<StateP> = (0..rng.gen_range(0..200)).map(|_| random_parent(&mut rng)).collect();
+
+            let memory = rng.gen_range(1..30) * size_of::<(StateH, StateP)>();
+            let mut buffers: Vec<SpillBuffer> = (0..3)
+                .map(|b| SpillBuffer::new(&dir, format!("trial {} buffer {}", trial, b), memory, &conf))
+                .collect();
+            let mut expected = TopK::for_search(&conf);
+            for (i, parent) in parents.iter().enumerate() {
+                buffers[i % 3].insert(parent.convert_state_h(), parent.clone());
+                expected.insert(parent.convert_state_h(), parent.parent_index, parent.clone());
+            }
+            let runs: Vec<PathBuf> = buffers.into_iter().flat_map(|b| b.finish()).collect();
+
+            assert_eq!(merge_runs(&runs, &conf), expected.into_vec());
+            assert!(runs.iter().all(|run| !run.exists()));
+        }
+        fs::remove_dir(&dir).ok();
+    }
+}
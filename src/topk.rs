@@ -0,0 +1,271 @@
+// Beam selection: keeping the best children of a depth.
+
+// A TopK keeps the best capacity states inserted into it, best meaning highest in StateH's order
+// (heuristic first), each with a value such as its parent.
+// A state inserted twice keeps the value with the lowest origin, so the result does not depend on
+// the order states arrive in; parents use their index as the origin.
+// With a family cap, at most family_cap states of one family (see FamilyKey) are kept, the best of them.
+// The states kept are the same as sorting everything inserted from best to worst and taking each
+// state whose family is not yet full, until capacity states are taken, and merging per-thread
+// TopKs gives the same states as inserting everything into one.
+// States are appended to a buffer, with a hash map from state to position for repeats. When the
+// buffer reaches twice the capacity it is cut back to the states that would be kept: a selection
+// without a family cap, a sort and Greedy with one. Once capacity states are kept, the worst of them
+// is a floor that later states must beat, so most children are rejected with one comparison; with a
+// family cap, so is the worst state of each full family for the states of that family.
+// Inserting is amortized constant time without a family cap, where the BTreeMap this replaced
+// paid a logarithmic insert and removal for every child; `bench-topk` compares the two.
+
+use crate::types::{FamilyKey, SearchConf, StateH};
+
+use fnv::FnvHashMap;
+
+#[derive(Clone, Debug)]
+pub struct TopK<V> {
+    capacity: usize,
+    family_key: FamilyKey,
+    family_cap: usize,
+    entries: Vec<(StateH, usize, V)>,
+    positions: FnvHashMap<StateH, usize>,
+    floor: Option<StateH>,
+    family_floors: FnvHashMap<u64, StateH>,
+}
+
+impl<V> TopK<V> {
+    pub fn new(capacity: usize) -> TopK<V> {
+        TopK::with_families(capacity, FamilyKey::BottomRow, 0)
+    }
+
+    pub fn with_families(capacity: usize, family_key: FamilyKey, family_cap: usize) -> TopK<V> {
+        TopK {
+            capacity,
+            family_key,
+            family_cap,
+            entries: vec![],
+            positions: FnvHashMap::default(),
+            floor: None,
+            family_floors: FnvHashMap::default(),
+        }
+    }
+
+    // The selection a beam search's settings ask for.
+    pub fn for_search(conf: &SearchConf) -> TopK<V> {
+        TopK::with_families(conf.beam_width, conf.family_key, conf.family_cap)
+    }
+
+    pub fn insert(&mut self, state: StateH, origin: usize, value: V) {
+        if let Some(floor) = &self.floor {
+            if state < *floor {
+                return;
+            }
+        }
+        if self.family_cap > 0 {
+            if let Some(floor) = self.family_floors.get(&self.family_key.family(&state.well)) {
+                if state < *floor {
+                    return;
+                }
+            }
+        }
+        if let Some(&i) = self.positions.get(&state) {
+            let existing = &mut self.entries[i];
+            if origin < existing.1 {
+                existing.1 = origin;
+                existing.2 = value;
+            }
+            return;
+        }
+
+        self.positions.insert(state.clone(), self.entries.len());
+        self.entries.push((state, origin, value));
+        if self.entries.len() >= 2 * self.capacity.max(8) {
+            self.compact();
+        }
+    }
+
+    // Cuts the buffer down to the states that would be kept, best first.
+    fn compact(&mut self) {
+        if self.family_cap == 0 {
+            if self.entries.len() > self.capacity {
+                if self.capacity > 0 {
+                    self.entries.select_nth_unstable_by(self.capacity - 1, |a, b| b.0.cmp(&a.0));
+                }
+                self.entries.truncate(self.capacity);
+            }
+        } else {
+            self.entries.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+            let mut greedy = Greedy::new(self.capacity, self.family_key, self.family_cap);
+            self.entries.retain(|(state, _, _)| greedy.take(state));
+
+            // A full family's worst state is a floor for that family.
+            self.family_floors.clear();
+            let mut counts: FnvHashMap<u64, usize> = FnvHashMap::default();
+            for (state, _, _) in &self.entries {
+                let family = self.family_key.family(&state.well);
+                let count = counts.entry(family).or_insert(0);
+                *count += 1;
+                if *count == self.family_cap {
+                    self.family_floors.insert(family, state.clone());
+                }
+            }
+        }
+
+        // The selection leaves the worst state kept at the end.
+        if self.entries.len() == self.capacity {
+            self.floor = self.entries.last().map(|(state, _, _)| state.clone());
+        }
+        self.positions.clear();
+        for (i, (state, _, _)) in self.entries.iter().enumerate() {
+            self.positions.insert(state.clone(), i);
+        }
+    }
+
+    pub fn merge(&mut self, other: TopK<V>) {
+        for (state, origin, value) in other.entries {
+            self.insert(state, origin, value);
+        }
+    }
+
+    // From the worst state to the best.
+    pub fn into_vec(mut self) -> Vec<(StateH, V)> {
+        self.compact();
+        self.entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        self.entries.into_iter().map(|(state, _, value)| (state, value)).collect()
+    }
+}
+
+// The same selection for states offered from best to worst: each is taken if it is not a repeat
+// of the state before it, its family has room and fewer than capacity states have been taken.
+
+pub struct Greedy {
+    capacity: usize,
+    family_key: FamilyKey,
+    family_cap: usize,
+    counts: FnvHashMap<u64, usize>,
+    taken: usize,
+    last: Option<StateH>,
+}
+
+impl Greedy {
+    pub fn new(capacity: usize, family_key: FamilyKey, family_cap: usize) -> Greedy {
+        Greedy {
+            capacity,
+            family_key,
+            family_cap,
+            counts: FnvHashMap::default(),
+            taken: 0,
+            last: None,
+        }
+    }
+
+    pub fn for_search(conf: &SearchConf) -> Greedy {
+        Greedy::new(conf.beam_width, conf.family_key, conf.family_cap)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.taken >= self.capacity
+    }
+
+    pub fn take(&mut self, state: &StateH) -> bool {
+        if self.is_full() || self.last.as_ref() == Some(state) {
+            return false;
+        }
+        self.last = Some(state.clone());
+
+        if self.family_cap > 0 {
+            let count = self.counts.entry(self.family_key.family(&state.well)).or_insert(0);
+            if *count == self.family_cap {
+                return false;
+            }
+            *count += 1;
+        }
+        self.taken += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::MAX_EFF_HEIGHT;
+    use crate::geometry::geometry;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_state(rng: &mut StdRng) -> StateH {
+        let g = geometry();
+        let mut state = StateH::new();
+        state.well[g.eff_height - 1] = rng.gen_range(1..5);
+        state.well[g.eff_height - 2] = rng.gen_range(0..3);
+        state.score = rng.gen_range(0..4);
+        state.hash = state.well[g.eff_height - 1] as u64 * 7 + state.well[g.eff_height - 2] as u64;
+        // Equal states always have equal heuristics.
+        state.heuristic = ((state.hash * 13 + state.score as u64 * 5) % 17) as i64;
+        state
+    }
+
+    // Sort everything from best to worst and take states while their family has room.
+    fn greedy(states: &[(StateH, usize)], capacity: usize, key: FamilyKey, cap: usize) -> Vec<(StateH, usize)> {
+        let mut sorted: Vec<(StateH, usize)> = states.to_vec();
+        sorted.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        sorted.dedup_by(|a, b| a.0 == b.0);
+
+        let mut counts: FnvHashMap<u64, usize> = FnvHashMap::default();
+        let mut kept = vec![];
+        for (state, origin) in sorted {
+            let count = counts.entry(key.family(&state.well)).or_insert(0);
+            if kept.len() < capacity && (cap == 0 || *count < cap) {
+                *count += 1;
+                kept.push((state, origin));
+            }
+        }
+        kept.reverse();
+        kept
+    }
+
+    #[test]
+    fn selection_matches_greedy_reference() {
+        let mut rng = StdRng::seed_from_u64(17);
+        for trial in 0..200 {
+            let capacity = rng.gen_range(1..12);
+            let cap = trial % 4;
+            let key = [FamilyKey::BottomRow, FamilyKey::TopSurface, FamilyKey::HeightProfile][trial % 3];
+            let states: Vec<(StateH, usize)> = (0..rng.gen_range(0..60))
+                .map(|_| (random_state(&mut rng), rng.gen_range(0..100)))
+                .collect();
+
+            let mut single = TopK::with_families(capacity, key, cap);
+            let mut parts = vec![TopK::with_families(capacity, key, cap); 3];
+            for (i, (state, origin)) in states.iter().enumerate() {
+                single.insert(state.clone(), *origin, *origin);
+                parts[i % 3].insert(state.clone(), *origin, *origin);
+            }
+            let mut merged = parts.pop().unwrap();
+            for part in parts {
+                merged.merge(part);
+            }
+
+            let expected = greedy(&states, capacity, key, cap);
+            assert_eq!(single.into_vec(), expected);
+            assert_eq!(merged.into_vec(), expected);
+        }
+    }
+
+    #[test]
+    fn families_follow_the_key() {
+        let g = geometry();
+        let left = 1 << (g.width - 1);
+
+        // One cell in the left column, then the same surface one row higher.
+        let mut low = [0; MAX_EFF_HEIGHT];
+        low[g.eff_height - 1] = left;
+        let mut high = [0; MAX_EFF_HEIGHT];
+        high[g.eff_height - 1] = g.max_row & !left;
+        high[g.eff_height - 2] = left;
+
+        assert_eq!(FamilyKey::TopSurface.family(&low), FamilyKey::TopSurface.family(&high));
+        assert_ne!(FamilyKey::HeightProfile.family(&low), FamilyKey::HeightProfile.family(&high));
+        assert_ne!(FamilyKey::BottomRow.family(&low), FamilyKey::BottomRow.family(&high));
+        assert_ne!(FamilyKey::TopSurface.family(&low), FamilyKey::TopSurface.family(&[0; MAX_EFF_HEIGHT]));
+    }
+}
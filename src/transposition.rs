@@ -0,0 +1,145 @@
+// A transposition table for beam searches.
+
+// The same well is reached many times, from different parents and at different depths, and its
+// heuristic (including the quiescence search) depends only on the well and the search's network and
+// settings. The table remembers the heuristic of every well network_heuristic() evaluates, for the
+// length of one beam search, so repeated wells skip the network entirely.
+// It is keyed by the well alone: the score does not change the heuristic.
+// The table is a fixed number of slots, each holding the last well that hashed to it, and is split
+// into shards with a lock each so worker threads rarely wait on each other.
+
+use crate::types::{State, WellT};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const SHARDS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    hash: u64,
+    well: WellT,
+    heuristic: f64,
+}
+
+#[derive(Debug)]
+pub struct TranspositionTable {
+    shards: Vec<Mutex<Vec<Option<Entry>>>>,
+    slots: usize,
+    lookups: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+impl TranspositionTable {
+    // A table of about size entries in total.
+    pub fn new(size: usize) -> TranspositionTable {
+        let slots = size.div_ceil(SHARDS).max(1);
+        TranspositionTable {
+            shards: (0..SHARDS).map(|_| Mutex::new(vec![None; slots])).collect(),
+            slots,
+            lookups: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self, hash: u64) -> (usize, usize) {
+        let shard = (hash % SHARDS as u64) as usize;
+        let slot = ((hash / SHARDS as u64) % self.slots as u64) as usize;
+        (shard, slot)
+    }
+
+    pub fn get(&self, state: &State) -> Option<f64> {
+        let (shard, slot) = self.slot(state.hash());
+        let entry = self.shards[shard].lock().unwrap()[slot];
+
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        match entry {
+            Some(e) if e.hash == state.hash() && e.well == *state.well() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(e.heuristic)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, state: &State, heuristic: f64) {
+        let (shard, slot) = self.slot(state.hash());
+        self.shards[shard].lock().unwrap()[slot] = Some(Entry {
+            hash: state.hash(),
+            well: *state.well(),
+            heuristic,
+        });
+    }
+
+    // Lookups and hits since the last call, for the per-depth statistics.
+    pub fn take_stats(&self) -> (usize, usize) {
+        (
+            self.lookups.swap(0, Ordering::Relaxed),
+            self.hits.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{network_heuristic, piece_placements};
+    use crate::geometry::geometry;
+    use crate::pieces::piece_count;
+    use crate::types::{PieceSelector, SearchConf, WeightT};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn only_the_same_well_hits() {
+        let table = TranspositionTable::new(1 << 10);
+        let children: Vec<State> = (0..piece_count())
+            .flat_map(|p| piece_placements(p, &State::new()))
+            .map(|pl| pl.state)
+            .collect();
+
+        table.insert(&children[0], 0.5);
+        assert_eq!(table.get(&children[0]), Some(0.5));
+
+        let mut scored = children[0].clone();
+        scored.score += 1;
+        assert_eq!(table.get(&scored), Some(0.5));
+
+        for child in &children {
+            if child.well() != children[0].well() {
+                assert_eq!(table.get(child), None);
+            }
+        }
+        let (lookups, hits) = table.take_stats();
+        assert_eq!(hits, 2);
+        assert!(lookups > hits);
+        assert_eq!(table.take_stats(), (0, 0));
+    }
+
+    #[test]
+    fn cached_heuristics_match_the_network() {
+        let g = geometry();
+        let weight = WeightT::test_network();
+        let mut conf = SearchConf::testing();
+        conf.piece_selector = PieceSelector::Hatetris;
+        conf.max_play = 4;
+        let table = TranspositionTable::new(1 << 12);
+
+        let mut rng = StdRng::seed_from_u64(15);
+        for _ in 0..10 {
+            let mut state = State::new();
+            for row in g.eff_height - 4..g.eff_height {
+                state.set_row(row, g.max_row & !(1 << rng.gen_range(0..g.width)));
+            }
+
+            let expected = network_heuristic(&state, &weight, &conf, 0, None, None);
+            for _ in 0..2 {
+                let cached = network_heuristic(&state, &weight, &conf, 0, None, Some(&table));
+                assert_eq!(cached, expected);
+            }
+            let (lookups, hits) = table.take_stats();
+            assert!(hits > 0 && lookups == 2 * expected.len());
+        }
+    }
+}
@@ -0,0 +1,325 @@
+// The parent tree of a beam search.
+
+// Searches with parents keep every well that is an ancestor of the current beam, so the best line
+// can be replayed and loop prevention can see each well's history. The tree is an arena: nodes are
+// appended as each depth's beam is chosen and refer to their parent by slot. Every node counts its
+// references, one per live child and one while it is in the beam; a beam well that gets no child in
+// the next beam drops to zero and releases its parent, and so on up the chain. Dead slots are reclaimed
+// by compacting the arena in order once they outnumber the live ones, so the work per depth is
+// proportional to the new beam, not to the whole history.
+// With saving on, each depth's beam is appended to one log, parents.bin, as fixed-size records (see
+// spill.rs) whose parent_index is the record number of the parent; the root is record 0. Nothing
+// already written is rewritten, and a search resumes by reading the log back up to its last depth.
+
+use crate::emulator::well_history;
+use crate::spill::{read_record, write_record, RECORD_BYTES};
+use crate::types::{StateP, WellT};
+
+use fnv::FnvHashSet;
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+
+#[derive(Clone, Debug)]
+pub struct ParentTree {
+    nodes: Vec<StateP>,
+    refs: Vec<u32>,
+    records: Vec<u64>,
+    frontier: Vec<usize>,
+    live: usize,
+    next_record: u64,
+}
+
+impl ParentTree {
+    pub fn new(root: StateP) -> ParentTree {
+        ParentTree {
+            nodes: vec![root],
+            refs: vec![1],
+            records: vec![0],
+            frontier: vec![0],
+            live: 1,
+            next_record: 1,
+        }
+    }
+
+    // Every slot, dead ones included; parent_index of a live node is its parent's slot.
+    pub fn nodes(&self) -> &Vec<StateP> {
+        &self.nodes
+    }
+
+    // The slots of the beam, from the worst well to the best.
+    pub fn frontier(&self) -> &Vec<usize> {
+        &self.frontier
+    }
+
+    // Live nodes: the beam and its ancestors.
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    pub fn history(&self, slot: usize) -> FnvHashSet<WellT> {
+        well_history(&self.nodes, slot)
+    }
+
+    // The wells from the root down to slot.
+    pub fn line(&self, slot: usize) -> Vec<StateP> {
+        let mut line = vec![self.nodes[slot].clone()];
+        let mut j = slot;
+        while self.nodes[j].depth > 0 {
+            j = self.nodes[j].parent_index;
+            line.push(self.nodes[j].clone());
+        }
+        line.reverse();
+        line
+    }
+
+    // Replaces the beam with its children, whose parent_index are slots of the current beam.
+    // Must not be called with an empty beam, which would release the whole tree.
+
+    pub fn advance(&mut self, beam: Vec<StateP>) {
+        for child in &beam {
+            self.refs[child.parent_index] += 1;
+        }
+        for slot in std::mem::take(&mut self.frontier) {
+            self.release(slot);
+        }
+        for child in beam {
+            self.frontier.push(self.nodes.len());
+            self.nodes.push(child);
+            self.refs.push(1);
+            self.records.push(self.next_record);
+            self.next_record += 1;
+            self.live += 1;
+        }
+
+        if self.nodes.len() - self.live > self.live {
+            self.compact();
+        }
+    }
+
+    fn release(&mut self, slot: usize) {
+        let mut j = slot;
+        loop {
+            self.refs[j] -= 1;
+            if self.refs[j] > 0 {
+                return;
+            }
+            self.live -= 1;
+            if self.nodes[j].depth == 0 {
+                return;
+            }
+            j = self.nodes[j].parent_index;
+        }
+    }
+
+    // Drops dead slots, keeping the live ones in order; parents always come before their children.
+    fn compact(&mut self) {
+        let mut slots = vec![usize::MAX; self.nodes.len()];
+        let mut kept = 0;
+        for j in 0..self.nodes.len() {
+            if self.refs[j] == 0 {
+                continue;
+            }
+            let mut node = self.nodes[j].clone();
+            if node.depth > 0 {
+                node.parent_index = slots[node.parent_index];
+            }
+            self.nodes[kept] = node;
+            self.refs[kept] = self.refs[j];
+            self.records[kept] = self.records[j];
+            slots[j] = kept;
+            kept += 1;
+        }
+        self.nodes.truncate(kept);
+        self.refs.truncate(kept);
+        self.records.truncate(kept);
+        for slot in self.frontier.iter_mut() {
+            *slot = slots[*slot];
+        }
+    }
+
+    // Appends the beam to the log at path, with record numbers for parents.
+
+    pub fn save_frontier(&self, path: &str) {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Could not open parent log.");
+        let mut writer = BufWriter::new(file);
+        for &slot in &self.frontier {
+            let mut record = self.nodes[slot].clone();
+            if record.depth > 0 {
+                record.parent_index = self.records[record.parent_index] as usize;
+            }
+            write_record(&mut writer, &record);
+        }
+        writer.flush().expect("Could not write parent log.");
+    }
+
+    // Rebuilds the tree of the search whose beam at depth is the last one kept; later records, from a
+    // depth that was not finished, are cut from the log.
+
+    pub fn load(path: &str, depth: usize) -> ParentTree {
+        let open = || BufReader::new(File::open(path).expect("Could not open parent log."));
+
+        // First the shape of the tree, to find the live records without holding every well.
+        let mut parent_records: Vec<u64> = vec![];
+        let mut frontier_records = vec![];
+        let mut reader = open();
+        while let Some(record) = read_record(&mut reader) {
+            if record.depth > depth {
+                break;
+            }
+            if record.depth == depth {
+                frontier_records.push(parent_records.len());
+            }
+            parent_records.push(if record.depth > 0 { record.parent_index as u64 } else { u64::MAX });
+        }
+        let count = parent_records.len();
+        let mut live = vec![false; count];
+        for &r in &frontier_records {
+            let mut j = r as u64;
+            while j != u64::MAX && !live[j as usize] {
+                live[j as usize] = true;
+                j = parent_records[j as usize];
+            }
+        }
+
+        let mut tree = ParentTree {
+            nodes: vec![],
+            refs: vec![],
+            records: vec![],
+            frontier: vec![],
+            live: 0,
+            next_record: count as u64,
+        };
+        let mut slots = vec![usize::MAX; count];
+        let mut reader = open();
+        for r in 0..count {
+            let mut node = read_record(&mut reader).unwrap();
+            if !live[r] {
+                continue;
+            }
+            if node.depth > 0 {
+                node.parent_index = slots[node.parent_index];
+                tree.refs[node.parent_index] += 1;
+            }
+            slots[r] = tree.nodes.len();
+            tree.nodes.push(node);
+            tree.refs.push(0);
+            tree.records.push(r as u64);
+            tree.live += 1;
+        }
+        for &r in &frontier_records {
+            tree.refs[slots[r]] += 1;
+            tree.frontier.push(slots[r]);
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_len((count * RECORD_BYTES) as u64))
+            .expect("Could not cut parent log.");
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::geometry;
+    use crate::types::{RowT, State};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn node(depth: usize, parent_index: usize, value: RowT) -> StateP {
+        let g = geometry();
+        let mut state = State::new();
+        state.set_row(g.eff_height - 1, value);
+        StateP {
+            well: *state.well(),
+            score: 0,
+            hash: state.hash(),
+            heuristic: value as f64,
+            min_prev_heuristic: 0.0,
+            depth,
+            parent_index,
+        }
+    }
+
+    // Random beams of a few children of random beam wells, depth after depth.
+    fn grow(tree: &mut ParentTree, rng: &mut StdRng, depth: usize, path: Option<&str>) -> Vec<Vec<StateP>> {
+        let g = geometry();
+        let mut beam = vec![];
+        for _ in 0..rng.gen_range(1..8) {
+            let parent = tree.frontier()[rng.gen_range(0..tree.frontier().len())];
+            beam.push(node(depth, parent, rng.gen_range(1..g.max_row)));
+        }
+        tree.advance(beam);
+        if let Some(path) = path {
+            tree.save_frontier(path);
+        }
+        tree.frontier().iter().map(|&slot| tree.line(slot)).collect()
+    }
+
+    #[test]
+    fn pruning_keeps_only_ancestors_of_the_beam() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let mut tree = ParentTree::new(node(0, usize::MAX, 0));
+        for depth in 1..300 {
+            for line in grow(&mut tree, &mut rng, depth, None) {
+                assert_eq!(line.iter().map(|n| n.depth).collect::<Vec<usize>>(), (0..=depth).collect::<Vec<usize>>());
+            }
+
+            let mut ancestors = FnvHashSet::default();
+            for &slot in tree.frontier() {
+                let mut j = slot;
+                while ancestors.insert(j) && tree.nodes()[j].depth > 0 {
+                    j = tree.nodes()[j].parent_index;
+                }
+            }
+            assert_eq!(tree.len(), ancestors.len());
+            assert!(tree.nodes().len() <= 2 * tree.len());
+        }
+    }
+
+    #[test]
+    fn the_log_rebuilds_the_tree() {
+        let path = std::env::temp_dir().join(format!("hatetris parents {}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::remove_file(path).ok();
+
+        let mut rng = StdRng::seed_from_u64(21);
+        let mut tree = ParentTree::new(node(0, usize::MAX, 0));
+        tree.save_frontier(path);
+        let mut lines = vec![];
+        for depth in 1..60 {
+            lines = grow(&mut tree, &mut rng, depth, Some(path));
+        }
+        // A depth that never finished.
+        let mut unfinished = tree.clone();
+        grow(&mut unfinished, &mut rng, 60, Some(path));
+
+        let mut loaded = ParentTree::load(path, 59);
+        assert_eq!(loaded.len(), tree.len());
+        let loaded_lines: Vec<Vec<StateP>> =
+            loaded.frontier().iter().map(|&slot| loaded.line(slot)).collect();
+        let wells = |lines: &Vec<Vec<StateP>>| -> Vec<Vec<(usize, u64)>> {
+            lines.iter().map(|line| line.iter().map(|n| (n.depth, n.hash)).collect()).collect()
+        };
+        assert_eq!(wells(&loaded_lines), wells(&lines));
+
+        // Searching on from the loaded tree logs the same records as going on from the original.
+        let next = grow(&mut loaded, &mut StdRng::seed_from_u64(22), 60, Some(path));
+        let expected = grow(&mut tree, &mut StdRng::seed_from_u64(22), 60, None);
+        assert_eq!(wells(&next), wells(&expected));
+        assert_eq!(ParentTree::load(path, 60).len(), tree.len());
+        std::fs::remove_file(path).ok();
+    }
+}
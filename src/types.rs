@@ -1,41 +1,118 @@
 #![allow(unused_parens)]
+// savefile-derive 0.8 puts the impls it derives inside named constants.
+#![allow(non_local_definitions)]
 
 use crate::constants::{
-    AEON, ALL_CONV, CHECKPOINTS, EFF_HEIGHT, HIDDEN, MASTER_BEAM_DEPTH, MASTER_BEAM_WIDTH,
-    MASTER_MAX_PLAY, MULTIPLIER, TRAINING_BEAM_DEPTH, TRAINING_BEAM_WIDTH, TRAINING_MAX_PLAY,
+    AEON, CHECKPOINTS, DEFAULT_SCORING, FAMILY_CAP, FAMILY_KEY, HIDDEN, LOOP_PREVENTION,
+    MASTER_BEAM_DEPTH, MASTER_BEAM_MEMORY, MASTER_BEAM_WIDTH, MASTER_MAX_PLAY, MASTER_NODE_BUDGET,
+    MASTER_TIME_BUDGET, MAX_EFF_HEIGHT, MULTIPLIER, PIECE_SELECTOR, TRAINING_BEAM_DEPTH,
+    TRAINING_BEAM_WIDTH, TRAINING_MAX_PLAY, TRAINING_NODE_BUDGET, TRAINING_TIME_BUDGET,
+    TRANSPOSITION_SIZE,
 };
-
+use crate::geometry::geometry;
+use crate::pieces::{piece_count, piece_set_name};
+use crate::seed::{seeded_rng, WEIGHT_STREAM};
+use crate::zobrist::{row_hash, well_hash};
+
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::{cmp::Ordering, fmt::Debug};
 //use std::{arch::x86_64::__m256d, simd::f64x4};
 
-use rand::thread_rng;
+use fnv::FnvHasher;
 use rand_distr::{Distribution, Normal};
 use savefile_derive::Savefile;
 
+// Rows need width + 2 bits and waveforms 4 * width + 4 bits, so u16/u64 covers wells up to 14 wide.
+// Build with --features wide for u32/u128, which covers wells up to 30 wide.
+#[cfg(not(feature = "wide"))]
 pub type RowT = u16;
+#[cfg(not(feature = "wide"))]
 pub type WaveT = u64;
-pub type WellT = [RowT; EFF_HEIGHT];
-pub type ScoreT = u16;
+#[cfg(feature = "wide")]
+pub type RowT = u32;
+#[cfg(feature = "wide")]
+pub type WaveT = u128;
+pub type WellT = [RowT; MAX_EFF_HEIGHT]; // Only the top geometry().eff_height rows are used.
+pub type ScoreT = u32; // Classic scoring gives 1200 points a tetris, too many for u16 in long games.
+
+// hash is the Zobrist hash of the well (see zobrist.rs). The well only changes through set_row(),
+// which keeps the hash up to date, so the two cannot disagree.
+// The hash is not saved, so loaded states must be rehashed; see database::load_states().
+// Equal states have equal hashes, so hashing and equality only compare the rows when the hashes match.
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Savefile)]
+#[derive(Clone, Debug, Savefile)]
 pub struct State {
-    pub well: WellT,
+    well: WellT,
     pub score: ScoreT,
+    #[savefile_ignore]
+    hash: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> State {
-        return State {
-            well: [0; EFF_HEIGHT],
+        State {
+            well: [0; MAX_EFF_HEIGHT],
             score: 0,
-        };
+            hash: 0,
+        }
+    }
+
+    pub fn from_well(well: WellT, score: ScoreT) -> State {
+        State {
+            well,
+            score,
+            hash: well_hash(&well),
+        }
+    }
+
+    pub fn well(&self) -> &WellT {
+        &self.well
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn set_row(&mut self, row: usize, value: RowT) {
+        self.hash ^= row_hash(row, self.well[row]) ^ row_hash(row, value);
+        self.well[row] = value;
+    }
+
+    // Only for states loaded from a file, which are saved without their hash.
+    pub fn rehash(&mut self) {
+        self.hash = well_hash(&self.well);
     }
 
     pub fn convert(state: StateH) -> State {
-        return State {
+        State {
             well: state.well,
             score: state.score,
-        };
+            hash: state.hash,
+        }
+    }
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.score == other.score && self.well == other.well
+    }
+}
+
+impl Eq for State {}
+
+impl Hash for State {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_u64(self.hash);
+        self.score.hash(hasher);
     }
 }
 
@@ -48,12 +125,12 @@ impl Ord for State {
 
         let mut second_cmp = Ordering::Equal;
         let mut i = 0;
-        while second_cmp == Ordering::Equal && i < EFF_HEIGHT {
+        while second_cmp == Ordering::Equal && i < MAX_EFF_HEIGHT {
             second_cmp = self.well[i].cmp(&other.well[i]);
             i += 1;
         }
 
-        return second_cmp;
+        second_cmp
     }
 }
 
@@ -63,25 +140,24 @@ impl PartialOrd for State {
     }
 }
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug)]
 pub struct StateH {
     pub well: WellT,
     pub score: ScoreT,
+    pub hash: u64,
     pub heuristic: i64,
 }
 
 impl PartialEq for StateH {
     fn eq(&self, other: &Self) -> bool {
-        if self.score != self.score {
-            return false;
-        } else {
-            for i in 0..EFF_HEIGHT {
-                if self.well[i] != other.well[i] {
-                    return false;
-                }
-            }
-        }
-        return true;
+        self.hash == other.hash && self.score == other.score && self.well == other.well
+    }
+}
+
+impl Hash for StateH {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_u64(self.hash);
+        self.score.hash(hasher);
     }
 }
 
@@ -99,14 +175,13 @@ impl Ord for StateH {
             return second_cmp;
         }
 
-        let mut third_cmp = Ordering::Equal;
-        let mut i = 0;
-        while third_cmp == Ordering::Equal && i < EFF_HEIGHT {
-            third_cmp = self.well[i].cmp(&other.well[i]);
-            i += 1;
+        // Ties are broken by the hash, so the rows are only compared for equal or colliding wells.
+        let third_cmp = self.hash.cmp(&other.hash);
+        if third_cmp != Ordering::Equal {
+            return third_cmp;
         }
 
-        return third_cmp;
+        self.well.cmp(&other.well)
     }
 }
 
@@ -116,13 +191,20 @@ impl PartialOrd for StateH {
     }
 }
 
+impl Default for StateH {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StateH {
     pub fn new() -> StateH {
-        return StateH {
-            well: [0; EFF_HEIGHT],
+        StateH {
+            well: [0; MAX_EFF_HEIGHT],
             score: 0,
+            hash: 0,
             heuristic: i64::MIN,
-        };
+        }
     }
 }
 
@@ -130,6 +212,8 @@ impl StateH {
 pub struct StateP {
     pub well: WellT,
     pub score: ScoreT,
+    #[savefile_ignore]
+    pub hash: u64,
     pub heuristic: f64,
     pub min_prev_heuristic: f64,
     pub depth: usize,
@@ -138,18 +222,20 @@ pub struct StateP {
 
 impl StateP {
     pub fn convert_state(&self) -> State {
-        return State {
-            well: self.well.clone(),
+        State {
+            well: self.well,
             score: self.score,
-        };
+            hash: self.hash,
+        }
     }
 
     pub fn convert_state_h(&self) -> StateH {
-        return StateH {
-            well: self.well.clone(),
+        StateH {
+            well: self.well,
             score: self.score,
+            hash: self.hash,
             heuristic: (self.heuristic * MULTIPLIER) as i64,
-        };
+        }
     }
 }
 
@@ -163,22 +249,19 @@ pub struct StateD {
 
 impl StateD {
     pub fn convert(state: &State, depth: i32, run_id: i32) -> StateD {
-        return StateD {
+        StateD {
             well: state.well,
             score: state.score,
-            depth: depth,
-            run_id: run_id,
-        };
+            depth,
+            run_id,
+        }
     }
 
     pub fn convert_tuple(state: &StateD) -> (State, (i32, i32)) {
-        return (
-            State {
-                well: state.well,
-                score: state.score,
-            },
+        (
+            State::from_well(state.well, state.score),
             (state.depth, state.run_id),
-        );
+        )
     }
 }
 
@@ -191,12 +274,12 @@ impl Ord for StateD {
 
         let mut second_cmp = Ordering::Equal;
         let mut i = 0;
-        while second_cmp == Ordering::Equal && i < EFF_HEIGHT {
+        while second_cmp == Ordering::Equal && i < MAX_EFF_HEIGHT {
             second_cmp = self.well[i].cmp(&other.well[i]);
             i += 1;
         }
 
-        return second_cmp;
+        second_cmp
     }
 }
 
@@ -223,7 +306,27 @@ impl GetInsertQuery for StateD {
         query.push_str(", ");
         query.push_str(&self.score.to_string());
         query.push_str(");");
-        return query;
+        query
+    }
+}
+
+// A piece placement and the state it produces.
+// rotation and x index the resting waveform as rotation + 4*x; the piece's 4x4 box spans
+// columns x - 2 to x + 1 and rows height - 4 to height - 1, with negative rows above the well.
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Placement {
+    pub piece: usize,
+    pub rotation: usize,
+    pub x: usize,
+    pub height: usize,
+    pub lines: usize,
+    pub state: State,
+}
+
+impl Placement {
+    pub fn wave_index(&self) -> usize {
+        self.rotation + 4 * self.x
     }
 }
 
@@ -231,12 +334,13 @@ pub struct StatePP(pub StateH);
 
 impl Debug for StatePP {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for x in self.0.well {
-            let mut row = format!("{:010b}\n", x)
+        let g = geometry();
+        for x in &self.0.well[..g.eff_height] {
+            let mut row = format!("{:0width$b}\n", x, width = g.width)
                 .chars()
                 .map(|x| if x == '1' { "#" } else { " " })
                 .collect::<String>();
-            row.extend("\n".chars());
+            row.push('\n');
             f.write_str(&row)?;
         }
         f.write_fmt(format_args!("Heuristic: {}\n", self.0.heuristic))?;
@@ -266,29 +370,36 @@ pub struct WeightDiscreteT {
 
 impl WeightDiscreteT {
     pub fn zero() -> WeightDiscreteT {
-        return WeightDiscreteT {
-            conv: vec![[0; HIDDEN]; ALL_CONV],
+        WeightDiscreteT {
+            conv: vec![[0; HIDDEN]; geometry().all_conv],
             hidden: [0; HIDDEN],
-        };
+        }
+    }
+}
+
+impl Default for WeightT {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl WeightT {
     pub fn zero() -> WeightT {
-        return WeightT {
-            conv: vec![[0.0; HIDDEN]; ALL_CONV],
+        WeightT {
+            conv: vec![[0.0; HIDDEN]; geometry().all_conv],
             hidden: [0.0; HIDDEN],
-        };
+        }
     }
 
     pub fn new() -> WeightT {
         let mut new_weights = WeightT::zero();
-        let mut rng = thread_rng();
+        let mut rng = seeded_rng(&[WEIGHT_STREAM]);
 
-        let dist_conv = Normal::new(0.0, 1.0 / (ALL_CONV as f64).sqrt()).unwrap();
+        let all_conv = geometry().all_conv;
+        let dist_conv = Normal::new(0.0, 1.0 / (all_conv as f64).sqrt()).unwrap();
         let dist_hidden = Normal::new(0.0, 1.0 / (HIDDEN as f64).sqrt()).unwrap();
 
-        for c in 0..ALL_CONV {
+        for c in 0..all_conv {
             for h in 0..HIDDEN {
                 new_weights.conv[c][h] = dist_conv.sample(&mut rng);
             }
@@ -296,14 +407,29 @@ impl WeightT {
         for h in 0..HIDDEN {
             new_weights.hidden[h] = dist_hidden.sample(&mut rng);
         }
-        return new_weights;
+        new_weights
+    }
+
+    // A fixed network for tests; any network will do, and new() is slow in debug builds.
+    #[cfg(test)]
+    pub fn test_network() -> WeightT {
+        let mut weight = WeightT::zero();
+        for (i, conv) in weight.conv.iter_mut().enumerate() {
+            for (j, w) in conv.iter_mut().enumerate() {
+                *w = ((7 * i + 13 * j) % 17) as f64 / 17.0 - 0.5;
+            }
+        }
+        for (j, w) in weight.hidden.iter_mut().enumerate() {
+            *w = (j % 5) as f64 / 5.0 - 0.4;
+        }
+        weight
     }
 
     pub fn to_discrete_network(&self) -> WeightDiscreteT {
         let mut new_weights = WeightDiscreteT::zero();
         let conversion_factor = 63.0 / 64.0;
 
-        for c in 0..ALL_CONV {
+        for c in 0..self.conv.len() {
             for h in 0..HIDDEN {
                 let tmp = self.conv[c][h] * 2048.0 * conversion_factor;
                 new_weights.conv[c][h] = match tmp {
@@ -322,7 +448,7 @@ impl WeightT {
                 _ => (tmp2 as i8),
             } as i16;
         }
-        return new_weights;
+        new_weights
     }
 
     // pub fn to_chunks(&self) -> WeightChunkT {
@@ -343,6 +469,165 @@ impl WeightT {
     // }
 }
 
+// How the next piece is chosen for a well.
+//	Hatetris: the worst piece for the player, as HATETRIS itself chooses it.
+//	Random: a uniformly random piece for every well.
+//	Bag: 7-bag for tetrominoes; every piece_count() moves contain each piece once, shuffled by the seed.
+//	Sequence: a fixed, scripted piece order, repeated once exhausted.
+// Bag and Sequence depend only on the move number, so every well at a given depth gets the same piece.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PieceSelector {
+    Hatetris,
+    Random,
+    Bag(u64),
+    Sequence(Vec<usize>),
+}
+
+impl PieceSelector {
+    // Reads a selector from the command line: hatetris, random, bag:SEED or seq:P,P,...
+    pub fn parse(text: &str) -> Result<PieceSelector, String> {
+        let (name, arg) = text.split_once(':').unwrap_or((text, ""));
+        match name {
+            "hatetris" => Ok(PieceSelector::Hatetris),
+            "random" => Ok(PieceSelector::Random),
+            "bag" => arg
+                .parse()
+                .map(PieceSelector::Bag)
+                .map_err(|_| format!("Bad bag seed {}.", arg)),
+            "seq" => {
+                let pieces: Result<Vec<usize>, _> = arg.split(',').map(|p| p.parse()).collect();
+                match pieces {
+                    Ok(pieces) if !pieces.is_empty() && pieces.iter().all(|&p| p < piece_count()) => {
+                        Ok(PieceSelector::Sequence(pieces))
+                    }
+                    _ => Err(format!("Bad piece sequence {}.", arg)),
+                }
+            }
+            _ => Err(format!("Unknown piece selector {}.", text)),
+        }
+    }
+
+    // The text parse() reads, as used in replay file names.
+    pub fn name(&self) -> String {
+        match self {
+            PieceSelector::Hatetris => String::from("hatetris"),
+            PieceSelector::Random => String::from("random"),
+            PieceSelector::Bag(seed) => format!("bag:{}", seed),
+            PieceSelector::Sequence(pieces) => {
+                let pieces: Vec<String> = pieces.iter().map(|p| p.to_string()).collect();
+                format!("seq:{}", pieces.join(","))
+            }
+        }
+    }
+}
+
+// Why a game ended, and with which piece.
+//	CannotSpawn: the piece overlaps the well or its walls where it spawns.
+//	LockedAboveLine: every place the piece can come to rest has cells above the line.
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum GameOver {
+    CannotSpawn { piece: usize },
+    LockedAboveLine { piece: usize },
+}
+
+// How many points clearing lines with a single piece is worth.
+//	Linear: one point per line, as in HATETRIS.
+//	Squared: lines * lines, rewarding multi-line clears.
+//	Classic: the original Nintendo table at level 0, 40/100/300/1200.
+// The rule is chosen once at startup, see scoring().
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScoringRule {
+    Linear,
+    Squared,
+    Classic,
+}
+
+impl ScoringRule {
+    pub fn points(&self, lines: usize) -> ScoreT {
+        match self {
+            ScoringRule::Linear => lines as ScoreT,
+            ScoringRule::Squared => (lines * lines) as ScoreT,
+            ScoringRule::Classic => [0, 40, 100, 300, 1200][lines],
+        }
+    }
+
+    // Scores never wrap; a run long enough to overflow ScoreT stops counting instead.
+    pub fn add(&self, score: ScoreT, lines: usize) -> ScoreT {
+        score.saturating_add(self.points(lines))
+    }
+
+    // The name parse() reads.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScoringRule::Linear => "linear",
+            ScoringRule::Squared => "squared",
+            ScoringRule::Classic => "classic",
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<ScoringRule, String> {
+        match text {
+            "linear" => Ok(ScoringRule::Linear),
+            "squared" => Ok(ScoringRule::Squared),
+            "classic" => Ok(ScoringRule::Classic),
+            _ => Err(format!("Unknown scoring rule {}.", text)),
+        }
+    }
+}
+
+static SCORING: OnceLock<ScoringRule> = OnceLock::new();
+
+pub fn scoring() -> ScoringRule {
+    *SCORING.get_or_init(|| match env::var("SCORING") {
+        Ok(rule) => ScoringRule::parse(&rule).unwrap_or_else(|e| panic!("{}", e)),
+        Err(_) => DEFAULT_SCORING,
+    })
+}
+
+// What makes two wells the same family, for the beam's family cap and its family statistics.
+//		BottomRow: the bottom row of the well.
+//		TopSurface: the shape of the stack's surface, the column heights less the lowest one.
+//		HeightProfile: the column heights themselves.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FamilyKey {
+    BottomRow,
+    TopSurface,
+    HeightProfile,
+}
+
+impl FamilyKey {
+    pub fn family(&self, well: &WellT) -> u64 {
+        let g = geometry();
+        if *self == FamilyKey::BottomRow {
+            return well[g.eff_height - 1] as u64;
+        }
+
+        let mut heights = vec![0; g.width];
+        for (c, height) in heights.iter_mut().enumerate() {
+            let bit = 1 << (g.width - 1 - c);
+            if let Some(top) = (0..g.eff_height).find(|&row| well[row] & bit != 0) {
+                *height = g.eff_height - top;
+            }
+        }
+        if *self == FamilyKey::TopSurface {
+            let lowest = *heights.iter().min().unwrap();
+            for height in heights.iter_mut() {
+                *height -= lowest;
+            }
+        }
+
+        let mut hasher = FnvHasher::default();
+        for height in heights {
+            hasher.write_usize(height);
+        }
+        hasher.finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SearchConf {
     pub beam_width: usize,
@@ -350,88 +635,160 @@ pub struct SearchConf {
     pub generation: usize,
     pub max_play: usize,
     pub quiescent: bool,
+    pub piece_selector: PieceSelector,
+    pub loop_prevention: bool, // Reads the history from the parent chain, so it needs parent to be set.
     pub parent: bool,
     pub save: bool,
     pub print: bool,
+    pub transposition_size: usize, // Entries in the transposition table, 0 for none.
+    pub family_key: FamilyKey,
+    pub family_cap: usize, // Most wells of one family kept in a beam, 0 for no cap.
+    pub beam_memory: usize, // Bytes for candidate children before they spill to disk, 0 to keep them in memory.
+    pub time_budget: Option<Duration>, // Wall-clock time for the search, checked after each depth.
+    pub node_budget: usize, // Children evaluated by the search, checked after each depth; 0 for no budget.
 }
 
 impl SearchConf {
     pub fn master(generation: usize) -> SearchConf {
-        return SearchConf {
+        SearchConf {
             beam_width: MASTER_BEAM_WIDTH,
             beam_depth: MASTER_BEAM_DEPTH,
-            generation: generation,
+            generation,
             max_play: MASTER_MAX_PLAY,
             quiescent: true,
+            piece_selector: PIECE_SELECTOR,
+            loop_prevention: LOOP_PREVENTION,
             parent: true,
             save: true,
             print: true,
-        };
+            transposition_size: TRANSPOSITION_SIZE,
+            family_key: FAMILY_KEY,
+            family_cap: FAMILY_CAP,
+            beam_memory: MASTER_BEAM_MEMORY,
+            time_budget: MASTER_TIME_BUDGET,
+            node_budget: MASTER_NODE_BUDGET,
+        }
     }
 
     pub fn training(generation: usize) -> SearchConf {
-        return SearchConf {
+        SearchConf {
             beam_width: TRAINING_BEAM_WIDTH,
             beam_depth: TRAINING_BEAM_DEPTH,
-            generation: generation,
+            generation,
             max_play: TRAINING_MAX_PLAY,
             quiescent: false,
+            piece_selector: PIECE_SELECTOR,
+            loop_prevention: false,
             parent: false,
             save: false,
             print: false,
-        };
+            transposition_size: 0,
+            family_key: FAMILY_KEY,
+            family_cap: 0,
+            beam_memory: 0,
+            time_budget: TRAINING_TIME_BUDGET,
+            node_budget: TRAINING_NODE_BUDGET,
+        }
     }
 
     pub fn testing() -> SearchConf {
-        return SearchConf {
+        SearchConf {
             beam_width: MASTER_BEAM_WIDTH,
             beam_depth: usize::MAX,
             generation: 0,
             max_play: MASTER_MAX_PLAY,
             quiescent: true,
+            piece_selector: PIECE_SELECTOR,
+            loop_prevention: LOOP_PREVENTION,
             parent: true,
             save: false,
             print: true,
-        };
+            transposition_size: TRANSPOSITION_SIZE,
+            family_key: FAMILY_KEY,
+            family_cap: FAMILY_CAP,
+            beam_memory: MASTER_BEAM_MEMORY,
+            time_budget: None,
+            node_budget: 0,
+        }
     }
 
     pub fn run_name(&self) -> String {
-        return format!("aeon-{}-gen-{}", AEON, self.generation);
+        format!("aeon-{}-gen-{}", AEON, self.generation)
+    }
+
+    // Runs with a non-default geometry keep their networks and replays apart from the default runs.
+    pub fn checkpoints_path(&self) -> String {
+        let g = geometry();
+        let mut path = String::from(CHECKPOINTS);
+        if !g.is_default() {
+            path += &format!(" {}x{}-{}", g.width, g.well_height, g.well_line);
+        }
+        if let Some(name) = piece_set_name() {
+            path += &format!(" {}", name);
+        }
+        // Always named: folders without a rule were scored with squared lines, and must not be resumed.
+        path += &format!(" {}", scoring().name());
+        path
     }
 
     pub fn aeon_path(&self) -> String {
-        return format!("{}/Aeon {}", CHECKPOINTS, AEON);
+        format!("{}/Aeon {}", self.checkpoints_path(), AEON)
+    }
+
+    pub fn seed_path(&self) -> String {
+        format!("{}/Seed.txt", self.aeon_path())
     }
 
     pub fn generation_path(&self) -> String {
-        return format!("{}/Generation {}", self.aeon_path(), self.generation);
+        format!("{}/Generation {}", self.aeon_path(), self.generation)
     }
 
     pub fn neural_network_path(&self) -> String {
-        return format!("{}/Network {}.bin", self.generation_path(), self.generation);
+        format!("{}/Network {}.bin", self.generation_path(), self.generation)
+    }
+
+    // A trained network that lost its promotion comparison; the generation keeps the previous one.
+    pub fn rejected_network_path(&self) -> String {
+        format!(
+            "{}/Rejected {}.bin",
+            self.generation_path(),
+            self.generation
+        )
+    }
+
+    pub fn promotion_path(&self) -> String {
+        format!("{}/Promotion.txt", self.generation_path())
     }
 
     pub fn replay_path(&self) -> String {
-        return format!("{}/Replay", self.generation_path());
+        format!("{}/Replay", self.generation_path())
+    }
+
+    pub fn spill_path(&self) -> String {
+        format!("{}/Spill", self.replay_path())
+    }
+
+    pub fn game_over_path(&self) -> String {
+        format!("{}/game_over.txt", self.replay_path())
     }
 
     pub fn training_path(&self) -> String {
-        return format!("{}/Training", self.generation_path());
+        format!("{}/Training", self.generation_path())
     }
 
     pub fn move_path(&self, depth: usize) -> String {
-        return format!("{}/move_{}.bin", self.replay_path(), depth);
+        format!("{}/move_{}.bin", self.replay_path(), depth)
     }
 
-    pub fn parent_path(&self, depth: usize) -> String {
-        return format!("{}/parent_{}.bin", self.replay_path(), depth);
+    pub fn parent_log_path(&self) -> String {
+        format!("{}/parents.bin", self.replay_path())
     }
 
     pub fn epoch_path(&self, epoch: isize) -> String {
-        return format!("{}/epoch_{}.bin", self.training_path(), epoch);
+        format!("{}/epoch_{}.bin", self.training_path(), epoch)
     }
 
     pub fn data_path(&self) -> String {
-        return format!("{}/all_epochs.bin", self.training_path());
+        format!("{}/all_epochs.bin", self.training_path())
     }
 }
@@ -0,0 +1,88 @@
+// Zobrist hashing of wells.
+
+// A well hashes to the XOR of one random key per row and per byte of that row's value:
+//		well_hash(well) = XOR over rows r and bytes b of ZOBRIST_KEYS[r][b][byte b of well[r]]
+// The key for an empty byte is 0, so empty rows add nothing and the empty well hashes to 0.
+// Changing row r from a to b changes the hash by row_hash(r, a) ^ row_hash(r, b), so placements
+// and line clears keep State::hash up to date by touching only the rows they change.
+// The keys come from a fixed seed rather than the run seed, so a well hashes the same in every run.
+
+use lazy_static::lazy_static;
+
+use crate::constants::MAX_EFF_HEIGHT;
+use crate::types::{RowT, WellT};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::mem::size_of;
+
+const ROW_BYTES: usize = size_of::<RowT>();
+const ZOBRIST_SEED: u64 = 0x5a0b_4157;
+
+lazy_static! {
+    pub static ref ZOBRIST_KEYS: Vec<[[u64; 256]; ROW_BYTES]> = {
+        let mut rng = StdRng::seed_from_u64(ZOBRIST_SEED);
+        let mut keys = vec![[[0; 256]; ROW_BYTES]; MAX_EFF_HEIGHT];
+        for row in keys.iter_mut() {
+            for byte in row.iter_mut() {
+                for value in byte.iter_mut().skip(1) {
+                    *value = rng.gen();
+                }
+            }
+        }
+        keys
+    };
+}
+
+pub fn row_hash(row: usize, value: RowT) -> u64 {
+    let keys = &ZOBRIST_KEYS[row];
+    let mut hash = 0;
+    for b in 0..ROW_BYTES {
+        hash ^= keys[b][((value >> (8 * b)) & 0xff) as usize];
+    }
+    hash
+}
+
+// The hash of a whole well, for wells built without placements.
+
+pub fn well_hash(well: &WellT) -> u64 {
+    let mut hash = 0;
+    for (row, &value) in well.iter().enumerate() {
+        if value != 0 {
+            hash ^= row_hash(row, value);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::piece_placements;
+    use crate::geometry::geometry;
+    use crate::pieces::piece_count;
+    use crate::types::State;
+
+    #[test]
+    fn placements_keep_the_hash_up_to_date() {
+        let g = geometry();
+        assert_eq!(well_hash(State::new().well()), 0);
+
+        let mut rng = StdRng::seed_from_u64(14);
+        let mut clears = 0;
+        for _ in 0..500 {
+            let mut state = State::new();
+            for row in rng.gen_range(0..g.eff_height)..g.eff_height {
+                state.set_row(row, rng.gen_range(0..g.max_row));
+            }
+            for p in 0..piece_count() {
+                for pl in piece_placements(p, &state) {
+                    assert_eq!(pl.state.hash(), well_hash(pl.state.well()));
+                    clears += pl.lines;
+                }
+            }
+        }
+        assert!(clears > 0);
+    }
+}